@@ -25,11 +25,18 @@ impl<'a> Decoder<'a> {
     fn read_byte_string(&mut self) -> Result<Value> {
         let mut buf = Vec::new();
         self.data.read_until(b':', &mut buf)?;
+        if buf.pop() != Some(b':') {
+            return Err(Error::Other("byte string without ':'".to_string()));
+        }
 
-        let n = str::from_utf8(&buf[..buf.len() - 1])?.parse::<i64>()?;
+        // the length can not exceed the rest of the data.
+        let n = str::from_utf8(&buf)?.parse::<usize>()?;
+        if n > self.data.len() {
+            return Err(Error::Other(format!("byte string length {} overflow", n)));
+        }
 
         buf.clear();
-        buf.resize_with(n as usize, Default::default);
+        buf.resize_with(n, Default::default);
 
         self.data.read_exact(&mut buf)?;
 
@@ -46,8 +53,11 @@ impl<'a> Decoder<'a> {
 
         let mut buf = Vec::new();
         self.data.read_until(b'e', &mut buf)?;
+        if buf.pop() != Some(b'e') {
+            return Err(Error::Other("integer without 'e'".to_string()));
+        }
 
-        let s = str::from_utf8(&buf[..buf.len() - 1])?;
+        let s = str::from_utf8(&buf)?;
         if s.starts_with("-0") || (s.len() > 1 && s.starts_with("0")) {
             return Err(Error::Other(format!("invalid integer '{}'", s)));
         }
@@ -88,15 +98,20 @@ impl<'a> Decoder<'a> {
         let mut res = HashMap::new();
         loop {
             let mut p = self.data.iter().peekable();
-            let ch = p.peek();
-            if ch.is_none() || ch == Some(&&b'e') {
-                self.skip_byte()?;
-                break;
+            match p.peek() {
+                Some(b'e') => {
+                    self.skip_byte()?;
+                    break;
+                }
+                Some(_) => {}
+                None => {
+                    return Err(Error::Other("eof stream".to_string()));
+                }
             }
 
             let key = match self.read_value()? {
                 Value::ByteString(inner) => inner,
-                _ => unreachable!(),
+                _ => return Err(Error::Other("dict key not byte string".to_string())),
             };
 
             let val = self.read_value()?;
@@ -126,7 +141,7 @@ pub fn from_bytes(v: &[u8]) -> Result<Value> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::bencode::map;
+    use crate::map;
 
     #[test]
     fn test_decode_byte_string() {
@@ -147,6 +162,8 @@ mod tests {
             b"3abc".as_ref(),
             b":abc".as_ref(),
             b"1:".as_ref(),
+            b"-1:a".as_ref(),
+            b"99999999999:a".as_ref(),
         ]
         .iter()
         .for_each(|x| {
@@ -229,7 +246,16 @@ mod tests {
             let dict = de.read_dict();
             assert!(dict.is_ok());
             assert_eq!(Value::from(v.clone()), dict.unwrap());
-        })
+        });
+
+        // invalid dict.
+        [b"d1:a".as_ref(), b"d".as_ref(), b"di1e1:ae".as_ref()]
+            .iter()
+            .for_each(|x| {
+                let mut de = Decoder::new(x);
+                let val = de.read_dict();
+                assert!(val.is_err());
+            });
     }
 
     #[test]
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use lru_cache::LruCache;
+
+use crate::util::rand_infohash_key;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+// votes needed before trusting the external ip.
+const MIN_VOTES: usize = 5;
+// remember the votes of the most recent voters only.
+const VOTERS_MAX: usize = 64;
+
+// crc32c of the masked ip with r in the top bits, see BEP 42.
+fn id_prefix(ip: &IpAddr, r: u8) -> u32 {
+    let mut buf = match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .zip(V4_MASK.iter())
+            .map(|(x, m)| x & m)
+            .collect::<Vec<u8>>(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .zip(V6_MASK.iter())
+            .map(|(x, m)| x & m)
+            .collect::<Vec<u8>>(),
+    };
+    buf[0] |= (r & 0x07) << 5;
+    crc32c::crc32c(&buf)
+}
+
+// local and private addresses are exempt from the id check.
+fn is_exempt(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+// make a node id bound to the external ip, r selects one of 8 id prefixes.
+pub fn make_id(ip: &IpAddr, r: u8) -> Vec<u8> {
+    let crc = id_prefix(ip, r);
+
+    let mut id = rand_infohash_key();
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id[19] = r & 0x07;
+    id
+}
+
+// check the first 21 bits of id against the ip of the node.
+pub fn is_valid_id(id: &[u8], ip: &IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+
+    if id.len() != 20 {
+        return false;
+    }
+
+    let crc = id_prefix(ip, id[19]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+// external ip discovery by majority vote over the 'ip' field of responses.
+#[derive(Debug)]
+pub struct IpVoter {
+    votes: LruCache<IpAddr, IpAddr>,
+    external: Option<IpAddr>,
+}
+
+impl IpVoter {
+    pub fn new() -> Self {
+        Self {
+            votes: LruCache::new(VOTERS_MAX),
+            external: None,
+        }
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external
+    }
+
+    // count the vote of voter, returns the external ip when the majority changed it.
+    pub fn vote(&mut self, voter: IpAddr, ip: IpAddr) -> Option<IpAddr> {
+        self.votes.insert(voter, ip);
+
+        let mut count: HashMap<IpAddr, usize> = HashMap::new();
+        for (_, ip) in self.votes.iter() {
+            *count.entry(*ip).or_insert(0) += 1;
+        }
+
+        let (winner, n) = count.into_iter().max_by_key(|(_, n)| *n)?;
+        if n < MIN_VOTES || n * 2 <= self.votes.len() || self.external == Some(winner) {
+            return None;
+        }
+
+        self.external = Some(winner);
+        self.external
+    }
+}
+
+impl Default for IpVoter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::from_hex;
+
+    #[test]
+    fn test_is_valid_id() {
+        // test vectors from BEP 42.
+        [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ]
+        .iter()
+        .for_each(|(ip, id)| {
+            let ip = ip.parse().unwrap();
+            let mut id = from_hex(id).unwrap();
+            assert!(is_valid_id(&id, &ip));
+
+            id[0] ^= 0x01;
+            assert!(!is_valid_id(&id, &ip));
+        });
+
+        // private addresses are exempt.
+        assert!(is_valid_id(&[0; 20], &"192.168.1.1".parse().unwrap()));
+        assert!(is_valid_id(&[0; 20], &"127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_make_id() {
+        let ip = "124.31.75.21".parse().unwrap();
+        for r in 0..8 {
+            let id = make_id(&ip, r);
+            assert_eq!(id[19], r);
+            assert!(is_valid_id(&id, &ip));
+            assert!(!is_valid_id(&id, &"124.31.75.22".parse().unwrap()));
+        }
+
+        let ip = "2001:db8::1".parse().unwrap();
+        assert!(is_valid_id(&make_id(&ip, 3), &ip));
+    }
+
+    #[test]
+    fn test_vote() {
+        let mut voter = IpVoter::new();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "5.6.7.8".parse().unwrap();
+
+        for i in 1..MIN_VOTES as u8 {
+            assert_eq!(voter.vote(IpAddr::from([10, 0, 0, i]), ip), None);
+        }
+
+        // the same voter is counted once.
+        assert_eq!(voter.vote(IpAddr::from([10, 0, 0, 1]), ip), None);
+
+        assert_eq!(voter.vote(IpAddr::from([10, 0, 0, 100]), ip), Some(ip));
+        assert_eq!(voter.external_ip(), Some(ip));
+
+        // a minority doesn't change the result.
+        assert_eq!(voter.vote(IpAddr::from([10, 0, 1, 1]), other), None);
+        assert_eq!(voter.external_ip(), Some(ip));
+    }
+}
@@ -1,12 +1,26 @@
-use btsniffer::{torrent, BlackList, Error, MetaWire, DHT};
+use btsniffer::config::{read_bootstrap_file, SEEDS};
+use btsniffer::coverage::TargetPolicy;
+use btsniffer::strategy;
+use btsniffer::{torrent, BlackList, Config, Error, Message, MetaWire, DHT};
+
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use anyhow::Result;
 use async_std::path::{Path, PathBuf};
-use async_std::{fs, task};
+use async_std::prelude::*;
+use async_std::{channel, fs, future, task};
 use bencode::Value;
 use log::{debug, error, info};
 use structopt::StructOpt;
 
+// interval of logging the DHT counters.
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// SIGINT and SIGTERM received so far.
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(StructOpt, Debug)]
 struct Opt {
     #[structopt(
@@ -23,6 +37,12 @@ struct Opt {
         default_value = "6881"
     )]
     port: String,
+    #[structopt(
+        long = "sockets",
+        help = "udp sockets receiving on the port in parallel, SO_REUSEPORT on linux",
+        default_value = "1"
+    )]
+    sockets: usize,
     #[structopt(
         short = "f",
         long = "friends",
@@ -30,6 +50,24 @@ struct Opt {
         default_value = "500"
     )]
     friends: usize,
+    #[structopt(
+        long = "burst",
+        help = "max friends to make with at once, above the per second rate",
+        default_value = "50"
+    )]
+    burst: usize,
+    #[structopt(
+        long = "ip-rate",
+        help = "max queries to answer per second from one ip, 0 for no limit",
+        default_value = "20"
+    )]
+    ip_rate: usize,
+    #[structopt(
+        long = "network-rate",
+        help = "max queries to answer per second from one /24 or /48 network, 0 for no limit",
+        default_value = "100"
+    )]
+    network_rate: usize,
     #[structopt(
         short = "t",
         long = "timeout",
@@ -44,6 +82,12 @@ struct Opt {
         default_value = "500"
     )]
     peers: usize,
+    #[structopt(
+        long = "candidates",
+        help = "max peers found by get_peers to download a torrent from when the announcing peer fails",
+        default_value = "8"
+    )]
+    candidates: usize,
     #[structopt(
         short = "b",
         long = "blacklist",
@@ -58,15 +102,142 @@ struct Opt {
         default_value = "./torrents/"
     )]
     dir: PathBuf,
+    #[structopt(
+        short = "s",
+        long = "state",
+        help = "the file to save the routing table across restarts",
+        default_value = "./dht.json"
+    )]
+    state: PathBuf,
+    #[structopt(
+        short = "B",
+        long = "bootstrap",
+        help = "nodes to join the DHT through, host:port or ip:port (default public routers)",
+        use_delimiter = true
+    )]
+    bootstrap: Vec<String>,
+    #[structopt(
+        long = "bootstrap-file",
+        help = "the file of nodes to join the DHT through, one host:port per line"
+    )]
+    bootstrap_file: Option<PathBuf>,
+    #[structopt(long = "no-bootstrap", help = "do not join the DHT through any node")]
+    no_bootstrap: bool,
+    #[structopt(
+        short = "n",
+        long = "identities",
+        help = "number of virtual node ids spread over the keyspace",
+        default_value = "1"
+    )]
+    identities: usize,
+    #[structopt(
+        long = "bep42",
+        help = "derive the node ids from the external ip (BEP 42)"
+    )]
+    bep42: bool,
+    #[structopt(
+        long = "enforce-bep42",
+        help = "prefer nodes whose id matches their address (BEP 42)"
+    )]
+    enforce_bep42: bool,
+    #[structopt(
+        long = "read-only",
+        help = "crawl without answering queries (BEP 43), no announces are sniffed"
+    )]
+    read_only: bool,
+    #[structopt(
+        long = "id-strategy",
+        help = "node id shown to remotes: local, neighbor, random or mirror",
+        default_value = "neighbor"
+    )]
+    id_strategy: String,
+    #[structopt(
+        long = "closeness",
+        help = "bytes of the remote id a neighbor id copies",
+        default_value = "15"
+    )]
+    closeness: usize,
+    #[structopt(
+        long = "targets",
+        help = "find_node targets of the crawl: random, sweep, sparse or infohash",
+        default_value = "sparse"
+    )]
+    targets: String,
+}
+
+// bootstrap nodes from flags and file, falling back to the public routers.
+fn bootstrap_nodes(opt: &Opt) -> Result<Vec<String>> {
+    if opt.no_bootstrap {
+        return Ok(Vec::new());
+    }
+
+    let mut nodes = opt.bootstrap.clone();
+    if let Some(path) = &opt.bootstrap_file {
+        nodes.extend(read_bootstrap_file(path.as_ref())?);
+    }
+
+    if nodes.is_empty() {
+        nodes = SEEDS.iter().map(|s| s.to_string()).collect();
+    }
+    Ok(nodes)
 }
 
 async fn run_server(opt: Opt) -> Result<()> {
     let blacklist = BlackList::new(opt.blsize);
-    let mut dht = DHT::new(&opt.addr, &opt.port, opt.friends, opt.peers);
-    let rx = dht.run().await?;
+    let config = Config {
+        addr: opt.addr.clone(),
+        port: opt.port.clone(),
+        sockets: opt.sockets,
+        friends: opt.friends,
+        burst: opt.burst,
+        ip_rate: opt.ip_rate,
+        network_rate: opt.network_rate,
+        peers: opt.peers,
+        state: Some(opt.state.clone().into()),
+        bootstrap: bootstrap_nodes(&opt)?,
+        identities: opt.identities,
+        bep42: opt.bep42,
+        enforce_bep42: opt.enforce_bep42,
+        read_only: opt.read_only,
+        id_strategy: strategy::from_name(&opt.id_strategy, opt.closeness)?,
+        targets: TargetPolicy::from_name(&opt.targets)?,
+        ..Config::default()
+    };
+
+    let mut dht = DHT::new(&config);
+    let (handle, rx) = dht.run().await?;
+
+    let this = dht.clone();
+    task::spawn(async move {
+        loop {
+            task::sleep(STATS_INTERVAL).await;
+            info!("stats: {}", this.stats());
+        }
+    });
+
+    // SIGINT or SIGTERM starts the shutdown, a second one exits at once.
+    let (signal, signaled) = channel::bounded(1);
+    ctrlc::set_handler(move || {
+        if SIGNALS.fetch_add(1, Ordering::SeqCst) + 1 >= 2 {
+            std::process::exit(1);
+        }
+        let _ = signal.try_send(());
+    })?;
+
+    // every fetch holds a clone, all dropped means no fetch in flight.
+    let (fetching, fetched) = channel::bounded::<()>(1);
+    // dropped to cancel the fetches still running at the shutdown deadline.
+    let (cancel, cancelled) = channel::bounded::<()>(1);
 
     loop {
-        let msg = rx.recv().await?;
+        let stopped = async {
+            let _ = signaled.recv().await;
+            None
+        };
+        let msg = match async { Some(rx.recv().await) }.race(stopped).await {
+            Some(msg) => msg?,
+            None => break,
+        };
 
         if blacklist.contains(&msg.peer) {
             debug!("peer {} in the blacklist, skip.", msg.peer);
@@ -80,29 +251,113 @@ async fn run_server(opt: Opt) -> Result<()> {
         }
 
         let timeout = opt.timeout;
+        let candidates = opt.candidates;
         let infohash_hex = msg.infohash_hex();
         let mut blist_clone = blacklist.clone();
+        let dht = dht.clone();
+        let fetching = fetching.clone();
+        let cancelled = cancelled.clone();
 
         task::spawn(async move {
-            let mut wire = MetaWire::new(&msg, timeout);
-            match wire.fetch().await {
+            let _fetching = fetching;
+            let fetch = fetch_meta(&dht, &msg, timeout, candidates, &mut blist_clone);
+            let cancel = async {
+                let _ = cancelled.recv().await;
+                Err(Error::Other("cancelled at shutdown".to_string()))
+            };
+            match fetch.race(cancel).await {
                 Ok(meta) => {
                     let _ = store_torrent(&path, &meta)
                         .await
                         .map_err(|e| debug!("store_torrent failed, {}", e));
 
                     match torrent::from_bytes(infohash_hex, &meta) {
-                        Ok(t) => println!("{}", serde_json::to_string(&t).unwrap()),
+                        Ok(mut t) => {
+                            let scrape = async { Some(dht.scrape(&msg.infohash).await) };
+                            let cancel = async {
+                                let _ = cancelled.recv().await;
+                                None
+                            };
+                            if let Some(scrape) = scrape.race(cancel).await {
+                                t.seeders = Some(scrape.seeders);
+                                t.leechers = Some(scrape.leechers);
+                            }
+                            println!("{}", serde_json::to_string(&t).unwrap());
+                        }
                         Err(e) => debug!("parse torrent failed, {}", e),
                     }
                 }
-                Err(e) => {
-                    debug!("fetch fail, {}, {} add black list.", e, msg.peer);
-                    blist_clone.insert(msg.peer);
-                }
+                Err(e) => debug!("fetch {} fail, {}.", msg.infohash_hex(), e),
             }
         });
     }
+
+    info!(
+        "stop accepting announces, wait {}s for fetches.",
+        opt.timeout
+    );
+    drop(fetching);
+    let deadline = Duration::from_secs(opt.timeout);
+    if future::timeout(deadline, fetched.recv()).await.is_err() {
+        // fetched torrents are still stored, only the fetches and scrapes end.
+        info!("fetches still running at the deadline, cancel them.");
+        drop(cancel);
+        let _ = fetched.recv().await;
+    }
+
+    handle.shutdown().await;
+    info!("stats: {}", dht.stats());
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+// fetch the metadata of msg from the announcing peer, then from up to
+// candidates peers a get_peers lookup of the infohash finds. peers which
+// fail go to the blacklist.
+async fn fetch_meta(
+    dht: &DHT,
+    msg: &Message,
+    timeout: u64,
+    candidates: usize,
+    blacklist: &mut BlackList,
+) -> Result<Vec<u8>, Error> {
+    let mut last = match MetaWire::new(msg, timeout).fetch().await {
+        Ok(meta) => return Ok(meta),
+        Err(e) => e,
+    };
+    debug!("fetch fail, {}, {} add black list.", last, msg.peer);
+    blacklist.insert(msg.peer);
+    if candidates == 0 {
+        return Err(last);
+    }
+
+    // dropping the stream ends the lookup.
+    let mut peers = dht.get_peers(&msg.infohash);
+    let mut tried = 0;
+    while tried < candidates {
+        let peer = match peers.next().await {
+            Some(x) => x,
+            None => break,
+        };
+        if peer == msg.peer || blacklist.contains(&peer) {
+            continue;
+        }
+        tried += 1;
+
+        let candidate = Message {
+            peer,
+            infohash: msg.infohash.clone(),
+        };
+        match MetaWire::new(&candidate, timeout).fetch().await {
+            Ok(meta) => return Ok(meta),
+            Err(e) => {
+                debug!("fetch fail, {}, candidate {} add black list.", e, peer);
+                blacklist.insert(peer);
+                last = e;
+            }
+        }
+    }
+    Err(last)
 }
 
 async fn join_torrent_path(path: &PathBuf, infohash_hex: String) -> PathBuf {
@@ -0,0 +1,111 @@
+use std::net::IpAddr;
+
+use sha1::Sha1;
+
+// BEP 33 filter size in bytes, m = 2048 bits and k = 2.
+pub const BLOOM_SIZE: usize = 256;
+
+const BITS: usize = BLOOM_SIZE * 8;
+
+// bloom filter of peer ips as used by DHT scrapes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Box<[u8; BLOOM_SIZE]>,
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self {
+            bits: Box::new([0; BLOOM_SIZE]),
+        }
+    }
+}
+
+impl BloomFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // filter received in BFsd or BFpe, none if the size is wrong.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() != BLOOM_SIZE {
+            return None;
+        }
+
+        let mut bf = Self::new();
+        bf.bits.copy_from_slice(buf);
+        Some(bf)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits[..]
+    }
+
+    pub fn insert(&mut self, ip: &IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::from(ip.octets()).digest().bytes(),
+            IpAddr::V6(ip) => Sha1::from(ip.octets()).digest().bytes(),
+        };
+
+        let index1 = (hash[0] as usize | (hash[1] as usize) << 8) % BITS;
+        let index2 = (hash[2] as usize | (hash[3] as usize) << 8) % BITS;
+        self.bits[index1 / 8] |= 1 << (index1 % 8);
+        self.bits[index2 / 8] |= 1 << (index2 % 8);
+    }
+
+    // union with the filter of another node.
+    pub fn merge(&mut self, other: &BloomFilter) {
+        self.bits
+            .iter_mut()
+            .zip(other.bits.iter())
+            .for_each(|(a, b)| *a |= b);
+    }
+
+    // estimated number of ips inserted.
+    pub fn estimate(&self) -> f64 {
+        let zeros: u32 = self.bits.iter().map(|x| x.count_zeros()).sum();
+        // a full filter would be infinite.
+        let zeros = zeros.max(1) as f64;
+        let m = BITS as f64;
+
+        (zeros / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_estimate() {
+        // the test vector of BEP 33.
+        let mut bf = BloomFilter::new();
+        for i in 0..256 {
+            bf.insert(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, i as u8)));
+        }
+        for i in 0..1000 {
+            bf.insert(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+
+        assert!((bf.estimate() - 1224.93).abs() < 0.01);
+        assert_eq!(BloomFilter::new().estimate(), 0.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut x = BloomFilter::new();
+        x.insert(&a);
+        let mut y = BloomFilter::new();
+        y.insert(&b);
+        y.insert(&a);
+
+        x.merge(&y);
+        assert_eq!(x, y);
+        assert_eq!(BloomFilter::from_bytes(x.as_bytes()), Some(x));
+        assert_eq!(BloomFilter::from_bytes(&[0; 5]), None);
+    }
+}
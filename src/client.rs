@@ -0,0 +1,65 @@
+use std::fmt;
+
+// names of the client codes seen in 'v'.
+const NAMES: [(&[u8; 2], &str); 7] = [
+    (b"BT", "BitTorrent"),
+    (b"GR", "GetRight"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"ML", "MLDonkey"),
+    (b"UM", "uTorrent Mac"),
+    (b"UT", "uTorrent"),
+];
+
+// a client and its version, from the 'v' of its KRPC messages: two letters
+// naming the client and two version bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientVersion {
+    // the client name, or its two letter code when unknown.
+    pub name: String,
+    // the two version bytes, major and minor for libtorrent, other clients
+    // have their own schemes.
+    pub version: [u8; 2],
+}
+
+impl ClientVersion {
+    // none when v is not a two letter code and two version bytes.
+    pub fn parse(v: &[u8]) -> Option<Self> {
+        let (code, version) = match v {
+            [a, b, x, y] if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() => {
+                ([*a, *b], [*x, *y])
+            }
+            _ => return None,
+        };
+
+        let name = match NAMES.iter().find(|(x, _)| **x == code) {
+            Some((_, name)) => name.to_string(),
+            None => String::from_utf8_lossy(&code).to_string(),
+        };
+        Some(Self { name, version })
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}.{}", self.name, self.version[0], self.version[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let client = ClientVersion::parse(b"LT\x01\x02").unwrap();
+        assert_eq!(client.name, "libtorrent");
+        assert_eq!(client.to_string(), "libtorrent 1.2");
+
+        let client = ClientVersion::parse(b"XY\x00\x07").unwrap();
+        assert_eq!(client.to_string(), "XY 0.7");
+
+        assert_eq!(ClientVersion::parse(b"LT\x01"), None);
+        assert_eq!(ClientVersion::parse(b"\x00\x01\x02\x03"), None);
+    }
+}
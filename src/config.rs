@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::coverage::TargetPolicy;
+use crate::strategy::{Neighbor, NodeIdStrategy, CLOSENESS};
+use crate::Result;
+
+// public routers to join the DHT.
+pub const SEEDS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// DHT configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    // local listen address.
+    pub addr: String,
+    // local listen port.
+    pub port: String,
+    // udp sockets sharing the port with SO_REUSEPORT, each with a receive loop (linux).
+    pub sockets: usize,
+    // max find_node queries to send per second, 0 for no limit.
+    pub friends: usize,
+    // max find_node queries to send at once, above the friends rate.
+    pub burst: usize,
+    // max queries to answer per second from one ip, 0 for no limit.
+    pub ip_rate: usize,
+    // max queries to answer per second from one /24 or /48 network, 0 for no limit.
+    pub network_rate: usize,
+    // capacity of the announce message channel and of every event subscription.
+    pub peers: usize,
+    // file to persist the routing table, none disables persistence.
+    pub state: Option<PathBuf>,
+    // host:port or ip:port of nodes to join through, may be empty.
+    pub bootstrap: Vec<String>,
+    // number of virtual node ids, spread evenly over the keyspace.
+    pub identities: usize,
+    // derive the node ids from the external ip (BEP 42), once it's known.
+    pub bep42: bool,
+    // prefer nodes whose id matches their address (BEP 42).
+    pub enforce_bep42: bool,
+    // interval between announces of DHT::announce.
+    pub announce_interval: Duration,
+    // max BEP 44 items to store for other nodes.
+    pub items: usize,
+    // max infohashes whose announced peers are kept for BEP 33 scrapes.
+    pub torrents: usize,
+    // send lookups and crawl without answering queries (BEP 43).
+    pub read_only: bool,
+    // the node id presented in crawl queries and get_peers responses.
+    pub id_strategy: Arc<dyn NodeIdStrategy>,
+    // how the crawl picks the targets of its find_node queries.
+    pub targets: TargetPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0".to_string(),
+            port: "6881".to_string(),
+            sockets: 1,
+            friends: 500,
+            burst: 50,
+            ip_rate: 20,
+            network_rate: 100,
+            peers: 500,
+            state: None,
+            bootstrap: SEEDS.iter().map(|s| s.to_string()).collect(),
+            identities: 1,
+            bep42: false,
+            enforce_bep42: false,
+            announce_interval: Duration::from_secs(15 * 60),
+            items: 1024,
+            torrents: 4096,
+            read_only: false,
+            id_strategy: Arc::new(Neighbor {
+                closeness: CLOSENESS,
+            }),
+            targets: TargetPolicy::Sparse,
+        }
+    }
+}
+
+// read bootstrap nodes from file, one host:port per line,
+// blank lines and lines starting with '#' are skipped.
+pub fn read_bootstrap_file(path: &Path) -> Result<Vec<String>> {
+    let data = fs::read_to_string(path)?;
+    Ok(parse_bootstrap(&data))
+}
+
+fn parse_bootstrap(data: &str) -> Vec<String> {
+    data.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bootstrap() {
+        let data = "# private dht\n127.0.0.1:6881\n\n  node.example.com:6881  \n[::1]:6881\n";
+        assert_eq!(
+            parse_bootstrap(data),
+            vec!["127.0.0.1:6881", "node.example.com:6881", "[::1]:6881"]
+        );
+        assert!(parse_bootstrap("").is_empty());
+    }
+}
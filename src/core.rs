@@ -0,0 +1,1196 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use async_std::channel::Sender;
+use log::{debug, info};
+
+use crate::bep42::{self, IpVoter};
+use crate::client::ClientVersion;
+use crate::coverage::{Coverage, TargetPolicy};
+use crate::event::{DhtEvent, Source};
+use crate::identity::{closest_identity, Identity};
+use crate::item::{immutable_target, Item};
+use crate::krpc::{
+    query_tid, Krpc, ParseError, QueryKind, Response, Want, ERROR_GENERIC, ERROR_METHOD_UNKNOWN,
+    ERROR_PROTOCOL,
+};
+use crate::lookup::ALPHA;
+use crate::node::Node;
+use crate::rate::KeyedRate;
+use crate::routing::{Inserted, K};
+use crate::stats::{ClientCounts, Stats};
+use crate::storage::{ItemStore, PeerStore};
+use crate::strategy::NodeIdStrategy;
+use crate::transaction::{Reply, Transactions};
+use crate::{Config, Error, Message, Result};
+
+// max outstanding queries.
+const TRANSACTIONS_MAX: usize = 16384;
+
+// max query sources whose rate is tracked.
+const SOURCES_MAX: usize = 65536;
+
+// what the core asks its driver to do.
+#[derive(Debug)]
+pub enum Output {
+    // send the datagram buf to addr.
+    Send { buf: Vec<u8>, addr: SocketAddr },
+    // send find_node to node as identity when the outbound rate allows, see
+    // Core::find_node. it may be dropped under load.
+    Crawl { node: Node, identity: usize },
+    Event(DhtEvent),
+}
+
+// the protocol state of a DHT node without any io: datagrams go in with the
+// address they came from and the time, datagrams to send and events come out.
+#[derive(Clone, Debug)]
+pub struct Core {
+    identities: Arc<Vec<Identity>>,
+    transactions: Transactions,
+    items: ItemStore,
+    announces: PeerStore,
+    voter: Arc<Mutex<IpVoter>>,
+    bep42: bool,
+    read_only: bool,
+    id_strategy: Arc<dyn NodeIdStrategy>,
+    targets: TargetPolicy,
+    coverage: Arc<Mutex<Coverage>>,
+    sources: Arc<KeyedRate>,
+    stats: Arc<Mutex<Stats>>,
+    // the wall clock at an instant, routing tables keep wall clock times.
+    epoch: (Instant, SystemTime),
+}
+
+impl Core {
+    // a core started at now, when the wall clock read wall.
+    pub fn new(identities: Vec<Identity>, config: &Config, now: Instant, wall: SystemTime) -> Self {
+        Self {
+            identities: Arc::new(identities),
+            transactions: Transactions::new(TRANSACTIONS_MAX),
+            items: ItemStore::new(config.items),
+            announces: PeerStore::new(config.torrents),
+            voter: Arc::new(Mutex::new(IpVoter::new())),
+            bep42: config.bep42,
+            read_only: config.read_only,
+            id_strategy: config.id_strategy.clone(),
+            targets: config.targets,
+            coverage: Arc::new(Mutex::new(Coverage::default())),
+            sources: Arc::new(KeyedRate::new(
+                config.ip_rate,
+                config.network_rate,
+                SOURCES_MAX,
+                now,
+            )),
+            stats: Arc::new(Mutex::new(Stats::default())),
+            epoch: (now, wall),
+        }
+    }
+
+    pub fn identities(&self) -> &[Identity] {
+        &self.identities
+    }
+
+    // the identity answering queries about key.
+    pub fn identity(&self, key: &[u8]) -> &Identity {
+        &self.identities[closest_identity(&self.identities, key)]
+    }
+
+    // external ip agreed by the majority of responders.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.voter.lock().unwrap().external_ip()
+    }
+
+    // a snapshot of the counters, with the current routing table size and
+    // keyspace coverage.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.prefixes = self.coverage.lock().unwrap().prefixes();
+        stats.nodes = self
+            .identities
+            .iter()
+            .map(|x| x.table.lock().unwrap().len())
+            .sum();
+        stats
+    }
+
+    // update the counters, the driver counts its io and deliveries here.
+    pub fn count<F: FnOnce(&mut Stats)>(&self, f: F) {
+        f(&mut self.stats.lock().unwrap());
+    }
+
+    // the datagram of query q to addr sent by identity as id,
+    // the reply goes to reply when set.
+    pub fn query(
+        &self,
+        addr: SocketAddr,
+        identity: usize,
+        id: &[u8],
+        q: QueryKind,
+        reply: Option<Sender<Reply>>,
+        now: Instant,
+    ) -> Result<Vec<u8>> {
+        let tid = self.transactions.insert(identity, addr, reply, now);
+        let m = Krpc::Query {
+            tid,
+            id: id.to_vec(),
+            q,
+            ro: self.read_only,
+            want: Want::default(),
+        };
+        m.encode()
+    }
+
+    // the datagram of a find_node to addr, sent with the id the strategy
+    // picks for target_id, for the target the policy picks.
+    pub fn find_node(
+        &self,
+        addr: SocketAddr,
+        identity: usize,
+        target_id: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>> {
+        let id = self
+            .id_strategy
+            .node_id(target_id, &self.identities[identity].id());
+        let target = self.coverage.lock().unwrap().target(self.targets);
+        let q = QueryKind::FindNode { target };
+        self.query(addr, identity, &id, q, None, now)
+    }
+
+    // handle the datagram buf received from addr at now.
+    pub fn handle(&self, buf: &[u8], addr: &SocketAddr, now: Instant) -> Result<Vec<Output>> {
+        let mut out = Vec::new();
+
+        let (m, v) = match Krpc::parse_versioned(buf) {
+            Ok((m, v)) => (Ok(m), v),
+            Err(e) => (Err(e), None),
+        };
+        let client = v.as_deref().and_then(ClientVersion::parse);
+        self.count(|s| {
+            s.packets_in += 1;
+            s.bytes_in += buf.len() as u64;
+            if m.is_ok() {
+                client_counts(s, client.as_ref()).messages += 1;
+            }
+            match &m {
+                Ok(Krpc::Query { q, .. }) => *s.queries.entry(q.method()).or_default() += 1,
+                Ok(Krpc::Response { .. }) => s.replies += 1,
+                Ok(Krpc::Error { .. }) => s.errors_received += 1,
+                Err(_) => s.decode_failures += 1,
+            }
+        });
+
+        let m = match m {
+            // read-only nodes do not answer queries (BEP 43).
+            Ok(Krpc::Query { .. }) if self.read_only => return Ok(out),
+            Ok(Krpc::Query { .. }) if !self.allow(addr, now) => return Ok(out),
+            Ok(m) => m,
+            // malformed queries are answered, anything else is dropped.
+            Err(e) => match query_tid(buf) {
+                Some(_) if self.read_only || !self.allow(addr, now) => return Ok(out),
+                Some(tid) => {
+                    debug!("bad query from {}, {}", addr, e);
+                    out.push(self.error(tid, e.code(), &e.to_string(), addr)?);
+                    return Ok(out);
+                }
+                None => return Err(e.into()),
+            },
+        };
+
+        match &m {
+            Krpc::Query { id, .. } => self.coverage.lock().unwrap().on_node(id),
+            Krpc::Response { r, .. } => self.coverage.lock().unwrap().on_node(&r.id),
+            Krpc::Error { .. } => {}
+        }
+
+        match m {
+            Krpc::Query {
+                tid, id, q, want, ..
+            } => {
+                let wall = self.wall_time(now);
+                for identity in self.identities.iter() {
+                    identity.table.lock().unwrap().on_query(&id, addr, wall);
+                }
+                let source = self.source(Some(&id), v, client, addr, now);
+                self.on_query(&tid, &source, q, want, now, &mut out)?
+            }
+            Krpc::Response { tid, r, ip } => {
+                let source = self.source(Some(&r.id), v, client, addr, now);
+                self.on_reply(&tid, r, ip, &source, now, &mut out)?
+            }
+            Krpc::Error { tid, code, msg } => {
+                debug!("on_error {} code: {}, description: {}", addr, code, msg);
+                // only errors answering our queries, they end the transaction.
+                self.transactions
+                    .remove(&tid, addr, now)
+                    .ok_or_else(|| Error::Other(format!("unknown transaction from {}", addr)))?;
+                let source = self.source(None, v, client, addr, now);
+                out.push(Output::Event(DhtEvent::ErrorReceived { source, code, msg }));
+            }
+        }
+        Ok(out)
+    }
+
+    // whether the source of a query is under its rate limit, replies to our
+    // queries are never limited.
+    fn allow(&self, addr: &SocketAddr, now: Instant) -> bool {
+        let ok = self.sources.try_acquire_at(addr.ip(), now);
+        if !ok {
+            self.count(|s| s.queries_dropped += 1);
+        }
+        ok
+    }
+
+    // periodic upkeep at now: queries left unanswered count as failures of their
+    // nodes, and idle buckets are refreshed with a find_node of a random target
+    // in them (BEP 5).
+    pub fn tick(&self, now: Instant) -> Result<Vec<Output>> {
+        let mut out = Vec::new();
+
+        for t in self.transactions.expire(now) {
+            let mut table = self.identities[t.identity].table.lock().unwrap();
+            table.on_failure(&t.addr);
+        }
+
+        let wall = self.wall_time(now);
+        for (i, identity) in self.identities.iter().enumerate() {
+            let targets = identity.table.lock().unwrap().refresh(wall);
+            for target in targets {
+                let nodes = identity.table.lock().unwrap().closest(&target, ALPHA);
+                for e in nodes {
+                    let q = QueryKind::FindNode {
+                        target: target.clone(),
+                    };
+                    let buf = self.query(e.addr, i, &identity.id(), q, None, now)?;
+                    out.push(Output::Send { buf, addr: e.addr });
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // the wall clock time at now.
+    fn wall_time(&self, now: Instant) -> SystemTime {
+        self.epoch.1 + now.saturating_duration_since(self.epoch.0)
+    }
+
+    // the node behind a message from addr at now, v is its client version
+    // and client the version parsed.
+    fn source(
+        &self,
+        id: Option<&[u8]>,
+        v: Option<Vec<u8>>,
+        client: Option<ClientVersion>,
+        addr: &SocketAddr,
+        now: Instant,
+    ) -> Source {
+        Source {
+            time: self.wall_time(now),
+            id: id.map(|x| x.to_vec()),
+            client,
+            v,
+            addr: *addr,
+        }
+    }
+
+    fn on_query(
+        &self,
+        tid: &[u8],
+        source: &Source,
+        q: QueryKind,
+        want: Want,
+        now: Instant,
+        out: &mut Vec<Output>,
+    ) -> Result<()> {
+        let (id, addr) = (source.id.as_deref().unwrap_or_default(), &source.addr);
+        let want = want.or_family(addr);
+        let method = q.method();
+        match &q {
+            QueryKind::GetPeers { info_hash, .. } | QueryKind::AnnouncePeer { info_hash, .. } => {
+                self.coverage.lock().unwrap().on_infohash(info_hash)
+            }
+            _ => {}
+        }
+
+        let res = match q {
+            QueryKind::Ping => Ok(self.on_ping(id)),
+            QueryKind::FindNode { target } => Ok(self.on_find_node(&target, want)),
+            QueryKind::GetPeers { info_hash, scrape } => {
+                let r = self.on_get_peers(id, &info_hash, scrape, want, addr, now);
+                out.push(Output::Event(DhtEvent::GetPeersQuery {
+                    source: source.clone(),
+                    info_hash,
+                }));
+                Ok(r)
+            }
+            QueryKind::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                seed,
+            } => summarize(&info_hash, port, implied_port, addr)
+                .and_then(|msg| self.on_announce_peer(&token, seed, msg, source, now, out)),
+            QueryKind::Get { target, seq } => Ok(self.on_get(&target, seq, want, addr, now)),
+            QueryKind::Put { token, item, cas } => self.on_put(&token, item, cas, addr, now),
+            QueryKind::SampleInfohashes { .. } => {
+                Err(ParseError::UnknownMethod(method.to_string()).into())
+            }
+        };
+
+        let r = match res {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("{} from {} fail, {}", method, addr, e);
+                let (code, msg) = match e {
+                    Error::Item(e) => (e.code(), e.to_string()),
+                    Error::Krpc(e) => (e.code(), e.to_string()),
+                    _ => (ERROR_GENERIC, "A Generic Error Ocurred".to_string()),
+                };
+                out.push(self.error(tid.to_vec(), code, &msg, addr)?);
+                return Ok(());
+            }
+        };
+
+        let m = Krpc::Response {
+            tid: tid.to_vec(),
+            r,
+            ip: Some(*addr),
+        };
+        out.push(Output::Send {
+            buf: m.encode()?,
+            addr: *addr,
+        });
+        Ok(())
+    }
+
+    // an error reply keeping the querier's transaction id.
+    fn error(&self, tid: Vec<u8>, code: i64, msg: &str, addr: &SocketAddr) -> Result<Output> {
+        self.count(|s| match code {
+            ERROR_GENERIC => s.errors_sent.generic += 1,
+            ERROR_PROTOCOL => s.errors_sent.protocol += 1,
+            ERROR_METHOD_UNKNOWN => s.errors_sent.method_unknown += 1,
+            _ => s.errors_sent.storage += 1,
+        });
+
+        let m = Krpc::Error {
+            tid,
+            code,
+            msg: msg.to_string(),
+        };
+        Ok(Output::Send {
+            buf: m.encode()?,
+            addr: *addr,
+        })
+    }
+
+    fn on_reply(
+        &self,
+        tid: &[u8],
+        r: Response,
+        ip: Option<SocketAddr>,
+        source: &Source,
+        now: Instant,
+        out: &mut Vec<Output>,
+    ) -> Result<()> {
+        let addr = &source.addr;
+        let t = self
+            .transactions
+            .remove(tid, addr, now)
+            .ok_or_else(|| Error::Other(format!("unknown transaction from {}", addr)))?;
+
+        // the responder is a good node of the identity which queried it. a full
+        // bucket makes room only when one of its questionable nodes fails a ping.
+        let wall = self.wall_time(now);
+        let identity = &self.identities[t.identity];
+        let stale = {
+            let mut table = identity.table.lock().unwrap();
+            match table.add(&r.id, *addr, wall) {
+                Inserted::New { replaced } => {
+                    out.push(Output::Event(DhtEvent::NodeDiscovered {
+                        source: source.clone(),
+                    }));
+                    if let Some(e) = replaced {
+                        let source = Source {
+                            time: e.last_seen,
+                            id: Some(e.id),
+                            v: None,
+                            client: None,
+                            addr: e.addr,
+                        };
+                        out.push(Output::Event(DhtEvent::NodeDropped { source }));
+                    }
+                    None
+                }
+                Inserted::Refreshed => None,
+                Inserted::Dropped => table.questionable(&r.id, wall),
+            }
+        };
+        if let Some(stale) = stale {
+            let buf = self.query(
+                stale,
+                t.identity,
+                &identity.id(),
+                QueryKind::Ping,
+                None,
+                now,
+            )?;
+            out.push(Output::Send { buf, addr: stale });
+        }
+
+        if let Some(ip) = ip {
+            self.on_ip_vote(addr, ip.ip());
+        }
+
+        if let Some(samples) = &r.samples {
+            out.push(Output::Event(DhtEvent::SampleInfohashes {
+                source: source.clone(),
+                samples: samples.clone(),
+                num: r.num,
+                interval: r.interval,
+            }));
+        }
+
+        // replies of lookups go to the lookup, they are not crawled.
+        if let Some(reply) = t.reply {
+            let _ = reply.try_send(Reply { addr: *addr, r });
+            return Ok(());
+        }
+
+        debug!("on_reply {} decode {} nodes.", addr, r.nodes.len());
+
+        for node in r.nodes {
+            out.push(Output::Crawl {
+                node,
+                identity: t.identity,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn on_ip_vote(&self, addr: &SocketAddr, ip: IpAddr) {
+        let external = match self.voter.lock().unwrap().vote(addr.ip(), ip) {
+            Some(x) => x,
+            None => return,
+        };
+        info!("external ip {}", external);
+
+        if !self.bep42 {
+            return;
+        }
+
+        // r keeps the ids of the identities apart, only 8 prefixes exist per ip.
+        for (i, identity) in self.identities.iter().enumerate() {
+            if !bep42::is_valid_id(&identity.id(), &external) {
+                identity.set_id(&bep42::make_id(&external, i as u8));
+            }
+        }
+    }
+
+    fn on_ping(&self, id: &[u8]) -> Response {
+        Response::new(&self.identity(id).id())
+    }
+
+    fn on_find_node(&self, target: &[u8], want: Want) -> Response {
+        let identity = self.identity(target);
+        Response {
+            nodes: closest_nodes(identity, target, want),
+            ..Response::new(&identity.id())
+        }
+    }
+
+    fn on_get_peers(
+        &self,
+        id: &[u8],
+        info_hash: &[u8],
+        scrape: bool,
+        want: Want,
+        addr: &SocketAddr,
+        now: Instant,
+    ) -> Response {
+        let identity = self.identity(info_hash);
+        let mut r = Response {
+            nodes: closest_nodes(identity, info_hash, want),
+            token: Some(identity.make_token(addr)),
+            ..Response::new(&self.id_strategy.node_id(id, &identity.id()))
+        };
+
+        if scrape {
+            if let Some((seeds, leechers)) = self.announces.scrape(info_hash, now) {
+                r.seeds = Some(seeds);
+                r.leechers = Some(leechers);
+            }
+        }
+        r
+    }
+
+    fn on_announce_peer(
+        &self,
+        token: &[u8],
+        seed: bool,
+        msg: Message,
+        source: &Source,
+        now: Instant,
+        out: &mut Vec<Output>,
+    ) -> Result<Response> {
+        // the token was handed out by the identity closest to the infohash.
+        let identity = self.identity(&msg.infohash);
+        if !identity.is_valid_token(token, &source.addr) {
+            self.count(|s| s.invalid_tokens += 1);
+            return Err(ParseError::Invalid("token").into());
+        }
+
+        self.announces.insert(&msg.infohash, msg.peer, seed, now);
+        self.count(|s| client_counts(s, source.client.as_ref()).announces += 1);
+        out.push(Output::Event(DhtEvent::AnnouncePeer {
+            source: source.clone(),
+            msg,
+            seed,
+        }));
+
+        Ok(Response::new(&identity.id()))
+    }
+
+    fn on_get(
+        &self,
+        target: &[u8],
+        seq: Option<i64>,
+        want: Want,
+        addr: &SocketAddr,
+        now: Instant,
+    ) -> Response {
+        let identity = self.identity(target);
+        let mut r = Response {
+            nodes: closest_nodes(identity, target, want),
+            token: Some(identity.make_token(addr)),
+            ..Response::new(&identity.id())
+        };
+
+        match self.items.get(target, now) {
+            Some(Item::Immutable(v)) => r.v = Some(v),
+            // the requestor already has seq, the value is left out.
+            Some(Item::Mutable(item)) => {
+                r.seq = Some(item.seq);
+                if seq.is_none_or(|x| item.seq > x) {
+                    r.k = Some(item.k);
+                    r.sig = Some(item.sig);
+                    r.v = Some(item.v);
+                }
+            }
+            None => {}
+        }
+        r
+    }
+
+    fn on_put(
+        &self,
+        token: &[u8],
+        item: Item,
+        cas: Option<i64>,
+        addr: &SocketAddr,
+        now: Instant,
+    ) -> Result<Response> {
+        let target = match &item {
+            Item::Immutable(v) => immutable_target(v)?,
+            Item::Mutable(item) => item.target(),
+        };
+
+        let identity = self.identity(&target);
+        if !identity.is_valid_token(token, addr) {
+            self.count(|s| s.invalid_tokens += 1);
+            return Err(ParseError::Invalid("token").into());
+        }
+
+        match item {
+            Item::Immutable(v) => self.items.put_immutable(v, now)?,
+            Item::Mutable(item) => self.items.put_mutable(item, cas, now)?,
+        };
+        Ok(Response::new(&identity.id()))
+    }
+}
+
+// the peer announced by an announce_peer query from addr.
+fn summarize(
+    info_hash: &[u8],
+    port: Option<i64>,
+    implied_port: Option<i64>,
+    addr: &SocketAddr,
+) -> Result<Message> {
+    if info_hash.len() != 20 {
+        return Err(ParseError::Invalid("info_hash").into());
+    }
+
+    // There is an optional argument called implied_port which value is either 0 or 1. If it is
+    // 1, the port argument is ignored and the source port of the UDP packet is used as the
+    // peer's port instead. Any other value counts as 0.
+    let peer_port = match implied_port {
+        Some(1) => i64::from(addr.port()),
+        _ => port.ok_or(ParseError::Missing("port"))?,
+    };
+    let peer_port = match u16::try_from(peer_port) {
+        Ok(x) if x != 0 => x,
+        _ => return Err(ParseError::Invalid("port").into()),
+    };
+
+    Ok(Message::new(addr.ip(), peer_port, info_hash))
+}
+
+// the counters of client, or of "unknown" nodes.
+fn client_counts<'a>(s: &'a mut Stats, client: Option<&ClientVersion>) -> &'a mut ClientCounts {
+    let name = client.map_or("unknown", |x| x.name.as_str());
+    s.clients.entry(name.to_string()).or_default()
+}
+
+// nodes closest to target from the routing table of identity, up to K of
+// every family in want.
+fn closest_nodes(identity: &Identity, target: &[u8], want: Want) -> Vec<Node> {
+    let table = identity.table.lock().unwrap();
+    let mut entries = Vec::new();
+    if want.n4 {
+        entries.extend(table.closest_where(target, K, |x| x.is_ipv4()));
+    }
+    if want.n6 {
+        entries.extend(table.closest_where(target, K, |x| x.is_ipv6()));
+    }
+    entries
+        .into_iter()
+        .map(|e| Node {
+            id: e.id,
+            addr: e.addr,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventKind;
+    use crate::identity::spread_ids;
+    use crate::routing::IDLE;
+    use crate::strategy::LocalId;
+    use async_std::channel::{self, Receiver};
+    use bencode::Value;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // cores wired to each other in memory, datagrams are delivered in order.
+    struct Network {
+        nodes: HashMap<SocketAddr, Core>,
+        events: Vec<(SocketAddr, DhtEvent)>,
+        now: Instant,
+    }
+
+    impl Network {
+        fn new(n: usize) -> Self {
+            Self::with_config(n, Config::default())
+        }
+
+        fn with_config(n: usize, config: Config) -> Self {
+            let now = Instant::now();
+
+            let nodes = spread_ids(n)
+                .into_iter()
+                .enumerate()
+                .map(|(i, id)| {
+                    let identities = vec![Identity::new(&id, false)];
+                    let core = Core::new(identities, &config, now, SystemTime::now());
+                    (addr(i), core)
+                })
+                .collect();
+
+            Self {
+                nodes,
+                events: Vec::new(),
+                now,
+            }
+        }
+
+        fn id(&self, i: usize) -> Vec<u8> {
+            self.nodes[&addr(i)].identities()[0].id()
+        }
+
+        // node i sends q to node j, returns where the reply goes.
+        fn query(&mut self, i: usize, j: usize, q: QueryKind) -> Receiver<Reply> {
+            let (tx, rx) = channel::unbounded();
+            let core = &self.nodes[&addr(i)];
+            let buf = core
+                .query(addr(j), 0, &self.id(i), q, Some(tx), self.now)
+                .unwrap();
+            self.run(vec![(addr(i), buf, addr(j))]);
+            rx
+        }
+
+        // deliver datagrams until no node has anything left to send.
+        fn run(&mut self, mut queue: Vec<(SocketAddr, Vec<u8>, SocketAddr)>) {
+            while !queue.is_empty() {
+                let (from, buf, to) = queue.remove(0);
+                let core = match self.nodes.get(&to) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                for out in core.handle(&buf, &from, self.now).unwrap_or_default() {
+                    match out {
+                        Output::Send { buf, addr } => queue.push((to, buf, addr)),
+                        Output::Crawl { node, identity } => {
+                            let buf = core.find_node(node.addr, identity, &node.id, self.now);
+                            queue.push((to, buf.unwrap(), node.addr));
+                        }
+                        Output::Event(e) => self.events.push((to, e)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::new("10.0.0.1".parse().unwrap(), 6881 + i as u16)
+    }
+
+    #[test]
+    fn test_ping() {
+        let mut net = Network::new(2);
+        let rx = net.query(0, 1, QueryKind::Ping);
+
+        let reply = rx.try_recv().unwrap();
+        assert_eq!(reply.addr, addr(1));
+        assert_eq!(reply.r.id, net.id(1));
+
+        // the responder joined the routing table of the querier.
+        let table = net.nodes[&addr(0)].identities()[0].table.lock().unwrap();
+        assert_eq!(table.closest(&net.id(1), 1)[0].addr, addr(1));
+    }
+
+    #[test]
+    fn test_crawl() {
+        let mut net = Network::new(3);
+
+        // node 1 learns node 2.
+        net.query(1, 2, QueryKind::Ping).try_recv().unwrap();
+
+        // find_node replies without a lookup are crawled, node 0 reaches node 2.
+        let buf = net.nodes[&addr(0)]
+            .find_node(addr(1), 0, &net.id(1), net.now)
+            .unwrap();
+        net.run(vec![(addr(0), buf, addr(1))]);
+        assert!(net.nodes[&addr(0)].stats().prefixes > 0);
+
+        let table = net.nodes[&addr(0)].identities()[0].table.lock().unwrap();
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn test_announce() {
+        // node 1 answers under its real id only, it is discovered once.
+        let config = Config {
+            id_strategy: Arc::new(LocalId),
+            ..Config::default()
+        };
+        let mut net = Network::with_config(2, config);
+        let info_hash = vec![7; 20];
+
+        let q = QueryKind::GetPeers {
+            info_hash: info_hash.clone(),
+            scrape: false,
+        };
+        let token = net.query(0, 1, q).try_recv().unwrap().r.token.unwrap();
+
+        let q = QueryKind::AnnouncePeer {
+            info_hash: info_hash.clone(),
+            port: Some(5555),
+            implied_port: Some(0),
+            token,
+            seed: true,
+        };
+        assert!(net.query(0, 1, q).try_recv().is_ok());
+
+        let kinds: Vec<_> = net.events.iter().map(|(to, e)| (*to, e.kind())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (addr(1), EventKind::GetPeersQuery),
+                (addr(0), EventKind::NodeDiscovered),
+                (addr(1), EventKind::AnnouncePeer),
+            ]
+        );
+        match &net.events[2].1 {
+            DhtEvent::AnnouncePeer { source, msg, seed } => {
+                assert_eq!(source.id, Some(net.id(0)));
+                assert_eq!(source.addr, addr(0));
+                assert_eq!(msg.peer, SocketAddr::new(addr(0).ip(), 5555));
+                assert_eq!(msg.infohash, info_hash);
+                assert!(seed);
+            }
+            x => panic!("unexpected event {:?}", x),
+        }
+
+        // a bad token is not acknowledged.
+        let q = QueryKind::AnnouncePeer {
+            info_hash,
+            port: Some(5555),
+            implied_port: Some(0),
+            token: b"bad".to_vec(),
+            seed: true,
+        };
+        assert!(net.query(0, 1, q).try_recv().is_err());
+        match &net.events[3..] {
+            [(to, DhtEvent::ErrorReceived { source, code, .. })] => {
+                assert_eq!(*to, addr(0));
+                assert_eq!(source.addr, addr(1));
+                assert_eq!(*code, ERROR_PROTOCOL);
+            }
+            x => panic!("unexpected events {:?}", x),
+        }
+        let stats = net.nodes[&addr(1)].stats();
+        assert_eq!(stats.errors_sent.protocol, 1);
+        assert_eq!(stats.invalid_tokens, 1);
+        assert_eq!(stats.queries["announce_peer"], 2);
+    }
+
+    #[test]
+    fn test_sample_events() {
+        let mut net = Network::new(2);
+        let target = vec![1; 20];
+
+        // our nodes answer sample_infohashes with an error of the transaction.
+        let q = QueryKind::SampleInfohashes {
+            target: target.clone(),
+        };
+        net.query(0, 1, q.clone());
+        match net.events.as_slice() {
+            [(to, DhtEvent::ErrorReceived { source, code, .. })] => {
+                assert_eq!(*to, addr(0));
+                assert_eq!(source.addr, addr(1));
+                assert_eq!(*code, ERROR_METHOD_UNKNOWN);
+            }
+            x => panic!("unexpected events {:?}", x),
+        }
+
+        // an error matching no transaction is not published.
+        let core = &net.nodes[&addr(0)];
+        let m = Krpc::Error {
+            tid: b"zz".to_vec(),
+            code: ERROR_GENERIC,
+            msg: "A Generic Error Ocurred".to_string(),
+        };
+        assert!(core
+            .handle(&m.encode().unwrap(), &addr(1), net.now)
+            .is_err());
+
+        // a sample answering our query is.
+        let buf = core
+            .query(addr(1), 0, &net.id(0), q, None, net.now)
+            .unwrap();
+        let r = Response {
+            samples: Some(vec![vec![7; 20]]),
+            num: Some(1),
+            interval: Some(60),
+            ..Response::new(&net.id(1))
+        };
+        let m = Krpc::Response {
+            tid: Krpc::parse(&buf).unwrap().tid().to_vec(),
+            r,
+            ip: None,
+        };
+        let out = core
+            .handle(&m.encode().unwrap(), &addr(1), net.now)
+            .unwrap();
+        let event = out.into_iter().find_map(|x| match x {
+            Output::Event(e @ DhtEvent::SampleInfohashes { .. }) => Some(e),
+            _ => None,
+        });
+        match event {
+            Some(DhtEvent::SampleInfohashes { samples, num, .. }) => {
+                assert_eq!(samples, vec![vec![7; 20]]);
+                assert_eq!(num, Some(1));
+            }
+            x => panic!("unexpected event {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_client_version() {
+        let mut net = Network::new(2);
+        let info_hash = vec![7; 20];
+
+        let q = QueryKind::GetPeers {
+            info_hash: info_hash.clone(),
+            scrape: false,
+        };
+        let token = net.query(0, 1, q).try_recv().unwrap().r.token.unwrap();
+
+        // node 0 announces as libtorrent 1.2.
+        let q = QueryKind::AnnouncePeer {
+            info_hash,
+            port: Some(5555),
+            implied_port: None,
+            token,
+            seed: false,
+        };
+        let buf = net.nodes[&addr(0)]
+            .query(addr(1), 0, &net.id(0), q, None, net.now)
+            .unwrap();
+        let mut m = bencode::from_bytes(&buf).unwrap();
+        if let Value::Dict(d) = &mut m {
+            d.insert(b"v".to_vec(), Value::from(b"LT\x01\x02".as_ref()));
+        }
+        net.run(vec![(addr(0), bencode::to_bytes(&m).unwrap(), addr(1))]);
+
+        let client = net.events.iter().find_map(|(_, e)| match e {
+            DhtEvent::AnnouncePeer { source, .. } => source.client.clone(),
+            _ => None,
+        });
+        assert_eq!(client.unwrap().to_string(), "libtorrent 1.2");
+
+        let stats = net.nodes[&addr(1)].stats();
+        let libtorrent = ClientCounts {
+            messages: 1,
+            announces: 1,
+        };
+        assert_eq!(stats.clients["libtorrent"], libtorrent);
+        assert_eq!(stats.clients["unknown"].messages, 1);
+        assert_eq!(stats.clients["unknown"].announces, 0);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let from: SocketAddr = "10.0.0.9:7000".parse().unwrap();
+        let infohash = [7; 20];
+        let port = |port, implied_port| {
+            summarize(&infohash, port, implied_port, &from).map(|msg| msg.peer.port())
+        };
+
+        // the announced port, unless implied_port is 1.
+        assert_eq!(port(Some(5555), None).unwrap(), 5555);
+        assert_eq!(port(Some(5555), Some(0)).unwrap(), 5555);
+        assert_eq!(port(Some(5555), Some(1)).unwrap(), 7000);
+        assert_eq!(port(None, Some(1)).unwrap(), 7000);
+        assert_eq!(port(Some(5555), Some(2)).unwrap(), 5555);
+
+        assert!(port(None, None).is_err());
+        assert!(port(None, Some(0)).is_err());
+        assert!(port(Some(0), None).is_err());
+        assert!(port(Some(-1), None).is_err());
+        assert!(port(Some(65536), None).is_err());
+        assert_eq!(port(Some(65535), None).unwrap(), 65535);
+
+        for len in [0, 19, 21] {
+            assert!(summarize(&vec![7; len], Some(5555), None, &from).is_err());
+        }
+
+        let msg = summarize(&infohash, Some(5555), None, &from).unwrap();
+        assert_eq!(msg.peer, "10.0.0.9:5555".parse().unwrap());
+        assert_eq!(msg.infohash, infohash.to_vec());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut net = Network::with_config(
+            2,
+            Config {
+                read_only: true,
+                ..Config::default()
+            },
+        );
+
+        let buf = net.nodes[&addr(0)]
+            .query(addr(1), 0, &net.id(0), QueryKind::Ping, None, net.now)
+            .unwrap();
+        match Krpc::parse(&buf).unwrap() {
+            Krpc::Query { ro, .. } => assert!(ro),
+            m => panic!("unexpected query {:?}", m),
+        }
+
+        // queries, even broken ones, are not answered.
+        let core = &net.nodes[&addr(1)];
+        assert!(core.handle(&buf, &addr(0), net.now).unwrap().is_empty());
+        let bad = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+        assert!(core.handle(bad, &addr(0), net.now).unwrap().is_empty());
+        assert!(net.query(0, 1, QueryKind::Ping).try_recv().is_err());
+    }
+
+    #[test]
+    fn test_source_limit() {
+        let mut net = Network::with_config(
+            2,
+            Config {
+                ip_rate: 2,
+                ..Config::default()
+            },
+        );
+
+        // replies of node 1 are not limited by node 0.
+        for _ in 0..2 {
+            assert!(net.query(0, 1, QueryKind::Ping).try_recv().is_ok());
+        }
+        assert!(net.query(0, 1, QueryKind::Ping).try_recv().is_err());
+        assert_eq!(net.nodes[&addr(1)].stats().queries_dropped, 1);
+        assert_eq!(net.nodes[&addr(0)].stats().queries_dropped, 0);
+
+        net.now += Duration::from_secs(1);
+        assert!(net.query(0, 1, QueryKind::Ping).try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_want() {
+        let net = Network::new(1);
+        let core = &net.nodes[&addr(0)];
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let wall = SystemTime::now();
+        let mut table = core.identities()[0].table.lock().unwrap();
+        table.insert(&[1; 20], addr(1), wall);
+        table.insert(&[2; 20], v6, wall);
+        drop(table);
+
+        let find_node = |want| {
+            let buf = Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: vec![3; 20],
+                q: QueryKind::FindNode {
+                    target: vec![4; 20],
+                },
+                ro: false,
+                want,
+            }
+            .encode()
+            .unwrap();
+            match core.handle(&buf, &addr(2), net.now).unwrap().as_slice() {
+                [Output::Send { buf, .. }] => match Krpc::parse(buf).unwrap() {
+                    Krpc::Response { r, .. } => r.nodes.into_iter().map(|x| x.addr).collect(),
+                    m => panic!("unexpected reply {:?}", m),
+                },
+                x => panic!("unexpected outputs {:?}", x),
+            }
+        };
+
+        // an ipv4 querier only gets ipv4 nodes unless it wants others.
+        let nodes: Vec<SocketAddr> = find_node(Want::default());
+        assert_eq!(nodes, vec![addr(1)]);
+        let nodes: Vec<SocketAddr> = find_node(Want {
+            n4: false,
+            n6: true,
+        });
+        assert_eq!(nodes, vec![v6]);
+        let nodes: Vec<SocketAddr> = find_node(Want { n4: true, n6: true });
+        assert_eq!(nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let net = Network::new(1);
+        let core = &net.nodes[&addr(0)];
+
+        let cases: Vec<(&[u8], i64)> = vec![
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe",
+                ERROR_METHOD_UNKNOWN,
+            ),
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe",
+                ERROR_PROTOCOL,
+            ),
+            (b"d1:q4:ping1:t2:aa1:y1:qe", ERROR_PROTOCOL),
+        ];
+
+        for (packet, code) in cases {
+            let out = core.handle(packet, &addr(1), net.now).unwrap();
+            match out.as_slice() {
+                [Output::Send { buf, addr: to }] => {
+                    assert_eq!(*to, addr(1));
+                    match Krpc::parse(buf).unwrap() {
+                        Krpc::Error { tid, code: c, .. } => {
+                            assert_eq!(tid, b"aa");
+                            assert_eq!(c, code);
+                        }
+                        m => panic!("unexpected reply {:?}", m),
+                    }
+                }
+                x => panic!("unexpected outputs {:?}", x),
+            }
+        }
+
+        // broken replies are not answered.
+        assert!(core.handle(b"d1:t2:aa1:y1:re", &addr(1), net.now).is_err());
+
+        let stats = core.stats();
+        assert_eq!(stats.errors_sent.method_unknown, 1);
+        assert_eq!(stats.errors_sent.protocol, 2);
+        assert_eq!(stats.decode_failures, 4);
+        assert_eq!(stats.packets_in, 4);
+    }
+
+    #[test]
+    fn test_put_get() {
+        let mut net = Network::new(2);
+        let v = Value::from("Hello World!");
+        let target = immutable_target(&v).unwrap();
+
+        let q = QueryKind::Get {
+            target: target.clone(),
+            seq: None,
+        };
+        let r = net.query(0, 1, q.clone()).try_recv().unwrap().r;
+        assert_eq!(r.v, None);
+
+        let q = QueryKind::Put {
+            token: r.token.unwrap(),
+            item: Item::Immutable(v.clone()),
+            cas: None,
+        };
+        assert!(net.query(0, 1, q).try_recv().is_ok());
+
+        let q = QueryKind::Get { target, seq: None };
+        assert_eq!(net.query(0, 1, q).try_recv().unwrap().r.v, Some(v));
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut net = Network::new(2);
+        assert!(net.query(0, 1, QueryKind::Ping).try_recv().is_ok());
+
+        // a ping which is never delivered fails once it expires.
+        let core = &net.nodes[&addr(0)];
+        core.query(addr(1), 0, &net.id(0), QueryKind::Ping, None, net.now)
+            .unwrap();
+        assert!(core.tick(net.now).unwrap().is_empty());
+
+        let later = net.now + Duration::from_secs(60);
+        assert!(core.tick(later).unwrap().is_empty());
+        let table = core.identities()[0].table.lock().unwrap();
+        assert_eq!(table.entries().next().unwrap().failures, 1);
+        drop(table);
+
+        // the bucket of node 1 idles, it is refreshed through node 1.
+        let idle = net.now + IDLE;
+        match core.tick(idle).unwrap().as_slice() {
+            [Output::Send { buf, addr: to }] => {
+                assert_eq!(*to, addr(1));
+                match Krpc::parse(buf).unwrap() {
+                    Krpc::Query {
+                        q: QueryKind::FindNode { .. },
+                        ..
+                    } => {}
+                    m => panic!("unexpected query {:?}", m),
+                }
+            }
+            x => panic!("unexpected outputs {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_late_reply() {
+        let mut net = Network::new(2);
+        let (tx, rx) = channel::unbounded();
+
+        let buf = net.nodes[&addr(0)]
+            .query(addr(1), 0, &net.id(0), QueryKind::Ping, Some(tx), net.now)
+            .unwrap();
+
+        // the reply comes back a minute after the query.
+        net.now += Duration::from_secs(60);
+        net.run(vec![(addr(0), buf, addr(1))]);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use lru_cache::LruCache;
+use rand::prelude::*;
+
+use crate::util::{rand_id_in_bucket, rand_infohash_key};
+use crate::{Error, Result};
+
+// regions of the keyspace coverage is tracked in, one per leading byte.
+const PREFIXES: usize = 256;
+
+// node ids remembered to count every node once, the least recently heard
+// are forgotten and count again when heard anew.
+const NODES_MAX: usize = 4096;
+
+// recently seen infohashes kept as targets.
+const INFOHASHES_MAX: usize = 256;
+
+// leading bits a target near an infohash shares with it.
+const NEAR_BITS: usize = 32;
+
+// how the crawl picks the target of its find_node queries. the nodes close
+// to the target answer, so the target decides which part of the keyspace
+// we learn about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetPolicy {
+    // a random key.
+    Random,
+    // a random key in each first byte prefix in turn, all 256 of them.
+    Sweep,
+    // a random key in the prefix we heard the least nodes from.
+    Sparse,
+    // a key near an infohash recently queried or announced.
+    Infohash,
+}
+
+impl TargetPolicy {
+    // the policy of name, one of random, sweep, sparse or infohash.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "random" => Ok(Self::Random),
+            "sweep" => Ok(Self::Sweep),
+            "sparse" => Ok(Self::Sparse),
+            "infohash" => Ok(Self::Infohash),
+            _ => Err(Error::Other(format!("unknown target policy '{}'", name))),
+        }
+    }
+}
+
+// the distinct nodes heard from in every prefix of the keyspace and the
+// infohashes seen lately, the state behind the target policies.
+#[derive(Debug)]
+pub struct Coverage {
+    heard: Vec<u64>,
+    nodes: LruCache<Vec<u8>, ()>,
+    infohashes: VecDeque<Vec<u8>>,
+    // the next prefix of the sweep.
+    cursor: usize,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            heard: vec![0; PREFIXES],
+            nodes: LruCache::new(NODES_MAX),
+            infohashes: VecDeque::with_capacity(INFOHASHES_MAX),
+            cursor: 0,
+        }
+    }
+}
+
+impl Coverage {
+    // a message came from the node id, only the first one of a node counts.
+    pub fn on_node(&mut self, id: &[u8]) {
+        if self.nodes.get_mut(id).is_some() {
+            return;
+        }
+        self.nodes.insert(id.to_vec(), ());
+
+        if let Some(x) = self.heard.get_mut(prefix(id)) {
+            *x += 1;
+        }
+    }
+
+    // infohash was queried or announced, malformed ones are left out.
+    pub fn on_infohash(&mut self, infohash: &[u8]) {
+        if infohash.len() != 20 {
+            return;
+        }
+        if self.infohashes.len() == INFOHASHES_MAX {
+            self.infohashes.pop_front();
+        }
+        self.infohashes.push_back(infohash.to_vec());
+    }
+
+    // prefixes we heard at least one node from.
+    pub fn prefixes(&self) -> usize {
+        self.heard.iter().filter(|x| **x > 0).count()
+    }
+
+    // the next find_node target under policy.
+    pub fn target(&mut self, policy: TargetPolicy) -> Vec<u8> {
+        match policy {
+            TargetPolicy::Random => rand_infohash_key(),
+            TargetPolicy::Sweep => {
+                let p = self.cursor;
+                self.cursor = (self.cursor + 1) % PREFIXES;
+                in_prefix(p)
+            }
+            TargetPolicy::Sparse => {
+                // ties go to a random one, not always the lowest prefix.
+                let start = thread_rng().gen_range(0, PREFIXES);
+                let p = (0..PREFIXES)
+                    .map(|i| (start + i) % PREFIXES)
+                    .min_by_key(|p| self.heard[*p])
+                    .unwrap_or(start);
+                in_prefix(p)
+            }
+            TargetPolicy::Infohash => match self.infohashes.iter().choose(&mut thread_rng()) {
+                Some(x) => rand_id_in_bucket(x, NEAR_BITS),
+                None => rand_infohash_key(),
+            },
+        }
+    }
+}
+
+// the prefix of a key.
+fn prefix(id: &[u8]) -> usize {
+    id.first().map_or(0, |x| *x as usize)
+}
+
+// a random key in prefix p.
+fn in_prefix(p: usize) -> Vec<u8> {
+    let mut key = rand_infohash_key();
+    key[0] = p as u8;
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::common_prefix_len;
+
+    #[test]
+    fn test_sweep() {
+        let mut coverage = Coverage::default();
+        for p in 0..PREFIXES * 2 {
+            let target = coverage.target(TargetPolicy::Sweep);
+            assert_eq!(prefix(&target), p % PREFIXES);
+        }
+    }
+
+    #[test]
+    fn test_sparse() {
+        let mut coverage = Coverage::default();
+        for p in 0..PREFIXES {
+            if p != 42 {
+                coverage.on_node(&in_prefix(p));
+            }
+        }
+        assert_eq!(coverage.prefixes(), PREFIXES - 1);
+
+        let target = coverage.target(TargetPolicy::Sparse);
+        assert_eq!(prefix(&target), 42);
+
+        // a chatty node counts once.
+        let chatty = in_prefix(42);
+        for _ in 0..10 {
+            coverage.on_node(&chatty);
+        }
+        coverage.on_node(&in_prefix(42));
+        assert_eq!(coverage.heard[42], 2);
+    }
+
+    #[test]
+    fn test_infohash() {
+        let mut coverage = Coverage::default();
+        assert_eq!(coverage.target(TargetPolicy::Infohash).len(), 20);
+
+        let infohash = rand_infohash_key();
+        coverage.on_infohash(&infohash);
+        let target = coverage.target(TargetPolicy::Infohash);
+        assert_eq!(common_prefix_len(&target, &infohash), NEAR_BITS);
+
+        assert!(TargetPolicy::from_name("bogus").is_err());
+    }
+}
@@ -1,322 +1,1120 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-use async_std::channel::{Receiver, Sender};
-use async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use async_std::channel::{Receiver, Sender, TrySendError};
+use async_std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use async_std::prelude::*;
+use async_std::stream::Stream;
 use async_std::sync::Arc;
-use async_std::{channel, task};
+use async_std::task::JoinHandle;
+use async_std::{channel, future, task};
 use bencode::Value;
 use log::{debug, info};
 use rand::prelude::*;
 
-use crate::node::decode_nodes;
-use crate::util::{neighbor_id, rand_infohash_key, rand_transation_id};
-use crate::{Error, Message, Rate, Result};
+use crate::bloom::BloomFilter;
+use crate::core::{Core, Output};
+use crate::event::{DhtEvent, EventKind};
+use crate::identity::{closest_identity, spread_ids, Identity};
+use crate::item::{check_value, immutable_target, mutable_target, Item, MutableItem};
+use crate::krpc::{QueryKind, Response};
+use crate::lookup::{Lookup, QUERY_TIMEOUT};
+use crate::node::Node;
+use crate::routing::{Status, K};
+use crate::state::{IdentityState, State};
+use crate::stats::Stats;
+use crate::transaction::Reply;
+use crate::transport::{self, Datagram, Transport};
+use crate::util::{rand_infohash_key, to_hex};
+use crate::{Config, Error, Message, Rate, Result};
 
-// recv buffer size.
-const BUFFER_SIZE_MAX: usize = 2048;
+// recv buffer size, big enough for BEP 44 items with their key, signature and nodes.
+const BUFFER_SIZE_MAX: usize = 8192;
 
-// trackers
-const SEEDS: [&'static str; 3] = [
-    "router.bittorrent.com:6881",
-    "dht.transmissionbt.com:6881",
-    "router.utorrent.com:6881",
-];
+// max datagrams received or crawled at once, see Transport::recv_batch.
+const BATCH_SIZE_MAX: usize = 32;
+
+// interval of saving the routing table to the state file.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// interval of expiring queries and refreshing idle buckets.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+// a node to crawl and the identity crawling it.
+type Crawl = (Node, usize);
+
+// running announce of DHT::announce, dropping it stops re-announcing.
+#[derive(Debug)]
+pub struct AnnounceHandle {
+    _cancel: Sender<()>,
+}
+
+impl AnnounceHandle {
+    pub fn cancel(self) {}
+}
+
+// running DHT of DHT::run, shutdown stops its background tasks. dropping it
+// stops them too, without waiting.
+#[derive(Debug)]
+pub struct RunHandle {
+    dht: DHT,
+    stop: Sender<()>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RunHandle {
+    // stop the receive loop, the join and the timers, wait for them to leave,
+    // then write the state file.
+    pub async fn shutdown(self) {
+        drop(self.stop);
+        for t in self.tasks {
+            t.await;
+        }
+
+        if let Err(e) = self.dht.save_state() {
+            info!("save state fail, {}", e);
+        }
+        info!("DHT {} shutdown.", self.dht.laddr);
+    }
+}
+
+// estimated swarm size of an infohash from BEP 33 scrapes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Scrape {
+    pub seeders: u64,
+    pub leechers: u64,
+}
+
+// a receiver of the events of some kinds, see DHT::subscribe.
+#[derive(Debug)]
+struct Subscriber {
+    kinds: Vec<EventKind>,
+    tx: Sender<DhtEvent>,
+}
 
 #[derive(Clone, Debug)]
 pub struct DHT {
     laddr: Arc<String>,
-    socket: Arc<Option<UdpSocket>>,
-    local_id: Arc<Vec<u8>>,
-    secret: Arc<Vec<u8>>,
-    limiter: Arc<Rate>,
+    transports: Arc<Vec<Box<dyn Transport>>>,
+    // the family of the transports, nodes of the other one are unreachable.
+    ipv6: bool,
+    // the transport of the next query, they take turns.
+    next: Arc<AtomicUsize>,
+    sockets: usize,
+    core: Core,
+    state: Arc<Option<PathBuf>>,
+    bootstrap: Arc<Vec<String>>,
+    announce_interval: Duration,
+    started: SystemTime,
     peers: usize,
+    // closed when the RunHandle is shut down or dropped.
+    stop: Receiver<()>,
+    // nodes to crawl, drained at the rate of limiter.
+    crawl: (Sender<Crawl>, Receiver<Crawl>),
+    limiter: Arc<Rate>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
 }
 
 impl DHT {
-    pub fn new(addr: &str, port: &str, limit: usize, peers: usize) -> Self {
+    pub fn new(config: &Config) -> Self {
+        let state = config.state.as_ref().and_then(|path| {
+            if !path.exists() {
+                return None;
+            }
+
+            State::load(path)
+                .map_err(|e| info!("load state {:?} fail, {}", path, e))
+                .ok()
+        });
+
+        // keep the stored ids unless the number of identities changed.
+        let n = config.identities.max(1);
+        let restored = state
+            .as_ref()
+            .filter(|s| s.identities.len() == n)
+            .and_then(|s| {
+                s.identities
+                    .iter()
+                    .map(|x| x.id())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .filter(|ids| ids.iter().all(|id| id.len() == 20));
+        let same_ids = restored.is_some();
+
+        let identities: Vec<Identity> = restored
+            .unwrap_or_else(|| spread_ids(n))
+            .into_iter()
+            .map(|id| Identity::new(&id, config.enforce_bep42))
+            .collect();
+
+        // restored nodes answered us before, query them ahead of the bootstrap nodes.
+        // nodes of regenerated identities are offered to every identity.
+        if let Some(state) = state {
+            let mut count = 0;
+            for (i, s) in state.identities.iter().enumerate() {
+                let targets = if same_ids {
+                    &identities[i..=i]
+                } else {
+                    &identities[..]
+                };
+                for node in s.nodes.iter() {
+                    let id = match node.id() {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    for identity in targets {
+                        let mut table = identity.table.lock().unwrap();
+                        if table.insert(&id, node.addr, node.last_seen()) {
+                            count += 1;
+                        }
+                    }
+                }
+            }
+            info!("restore {} nodes from state.", count);
+        }
+
         Self {
-            laddr: Arc::new(format!("{}:{}", addr, port)),
-            socket: Arc::new(None),
-            local_id: Arc::new(rand_infohash_key()),
-            secret: Arc::new(rand_infohash_key()),
-            limiter: Arc::new(Rate::new(limit)),
-            peers: peers,
+            laddr: Arc::new(format!("{}:{}", config.addr, config.port)),
+            transports: Arc::new(Vec::new()),
+            ipv6: false,
+            next: Arc::new(AtomicUsize::new(0)),
+            sockets: config.sockets,
+            core: Core::new(identities, config, Instant::now(), SystemTime::now()),
+            state: Arc::new(config.state.clone()),
+            bootstrap: Arc::new(config.bootstrap.clone()),
+            announce_interval: config.announce_interval,
+            started: SystemTime::now(),
+            peers: config.peers,
+            stop: channel::bounded(1).1,
+            crawl: channel::bounded(crawl_backlog(config)),
+            limiter: Arc::new(Rate::new(config.friends, config.burst)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
-    pub async fn run(&mut self) -> Result<Receiver<Message>> {
-        info!("DHT listen {}", self.laddr);
+    pub async fn run(&mut self) -> Result<(RunHandle, Receiver<Message>)> {
+        info!(
+            "DHT listen {} with {} sockets",
+            self.laddr,
+            self.sockets.max(1)
+        );
+
+        let transports = transport::bind(&self.laddr, self.sockets).await?;
+        Ok(self.run_on_all(transports))
+    }
+
+    // run on the given transport instead of an udp socket bound to the
+    // configured address.
+    pub fn run_on(&mut self, transport: Box<dyn Transport>) -> (RunHandle, Receiver<Message>) {
+        self.run_on_all(vec![transport])
+    }
+
+    // run on several transports, each with its own receive loop. replies leave
+    // through the transport the query came in, queries take turns.
+    pub fn run_on_all(
+        &mut self,
+        transports: Vec<Box<dyn Transport>>,
+    ) -> (RunHandle, Receiver<Message>) {
+        self.ipv6 = transports
+            .first()
+            .and_then(|x| x.local_addr().ok())
+            .is_some_and(|x| x.is_ipv6());
+        self.transports = Arc::new(transports);
 
-        let sock = UdpSocket::bind(self.laddr.as_ref()).await?;
-        self.socket = Arc::new(Some(sock));
+        let (stop, stopped) = channel::bounded(1);
+        self.stop = stopped;
 
         let (tx, rx) = channel::bounded(self.peers);
 
-        self.start_message_handler(tx);
-        self.start_join();
+        let mut tasks: Vec<_> = (0..self.transports.len())
+            .map(|i| self.start_message_handler(i, tx.clone()))
+            .collect();
+        tasks.push(self.start_join());
+        tasks.push(self.start_maintenance());
+        tasks.push(self.start_crawler());
+        tasks.extend(self.start_state_saver());
+
+        let handle = RunHandle {
+            dht: self.clone(),
+            stop,
+            tasks,
+        };
+        (handle, rx)
+    }
+
+    // spawn a background task of run_on, it leaves when the DHT stops.
+    fn spawn<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let stop = self.stop.clone();
+        task::spawn(f.race(async move {
+            let _ = stop.recv().await;
+        }))
+    }
+
+    // external ip agreed by the majority of responders.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.core.external_ip()
+    }
+
+    // a snapshot of the counters since the DHT started.
+    pub fn stats(&self) -> Stats {
+        self.core.stats()
+    }
+
+    // write the ids and the routing tables to the state file.
+    pub fn save_state(&self) -> Result<()> {
+        let path = match &*self.state {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // bad nodes stopped answering, they are not worth a restart.
+        let now = SystemTime::now();
+        let identities: Vec<IdentityState> = self
+            .core
+            .identities()
+            .iter()
+            .map(|x| {
+                let entries: Vec<_> = x
+                    .table
+                    .lock()
+                    .unwrap()
+                    .entries()
+                    .filter(|e| e.status(now) != Status::Bad)
+                    .cloned()
+                    .collect();
+                IdentityState::new(&x.id(), &entries)
+            })
+            .collect();
+        State { identities }.save(path)?;
 
-        Ok(rx)
+        debug!(
+            "save {} identities to state {:?}.",
+            self.core.identities().len(),
+            path
+        );
+        Ok(())
     }
 
-    fn start_join(&self) {
+    fn start_join(&self) -> JoinHandle<()> {
         const DHT_JOIN_COUNT: usize = 6;
 
         info!("start join DHT.");
 
         let this = self.clone();
-        task::spawn(async move {
-            for _ in 0..DHT_JOIN_COUNT {
-                for seed in SEEDS.iter() {
-                    match this.find_node(seed, &rand_infohash_key()).await {
-                        Ok(n) => debug!("start_join find_node send {}, {} bytes", seed, n),
-                        Err(e) => debug!("start_join find_node fail, {}", e),
-                    }
+        self.spawn(async move {
+            for round in 0..DHT_JOIN_COUNT {
+                for identity in 0..this.core.identities().len() {
+                    this.join(identity, round == 0).await;
                 }
 
                 let n = thread_rng().gen_range(2, 6);
                 task::sleep(Duration::from_secs(n)).await;
             }
             debug!("join work leave.");
-        });
+        })
+    }
+
+    async fn join(&self, identity: usize, first_round: bool) {
+        let nodes = self.join_nodes(identity, first_round);
+
+        if nodes.is_empty() {
+            for seed in self.bootstrap.iter() {
+                match self
+                    .find_node(seed.as_str(), identity, &rand_infohash_key())
+                    .await
+                {
+                    Ok(n) => debug!("start_join find_node send {}, {} bytes", seed, n),
+                    Err(e) => debug!("start_join find_node fail, {}", e),
+                }
+            }
+        }
+
+        for addr in nodes {
+            match self.find_node(addr, identity, &rand_infohash_key()).await {
+                Ok(n) => debug!("start_join find_node send {}, {} bytes", addr, n),
+                Err(e) => debug!("start_join find_node fail, {}", e),
+            }
+        }
     }
 
-    fn start_message_handler(&mut self, tx: Sender<Message>) {
-        let mut this = self.clone();
+    // known nodes to join through, empty means falling back to the bootstrap nodes.
+    // restored nodes get one round to answer before the bootstrap nodes are used.
+    fn join_nodes(&self, identity: usize, first_round: bool) -> Vec<SocketAddr> {
+        let identity = &self.core.identities()[identity];
+        let table = identity.table.lock().unwrap();
+        let answered = table.entries().any(|e| e.last_seen >= self.started);
 
-        task::spawn(async move {
+        if !answered && !first_round {
+            return Vec::new();
+        }
+
+        table
+            .closest(table.local_id(), K * 8)
+            .into_iter()
+            .map(|e| e.addr)
+            .collect()
+    }
+
+    fn start_state_saver(&self) -> Option<JoinHandle<()>> {
+        if self.state.is_none() {
+            return None;
+        }
+
+        let this = self.clone();
+        Some(self.spawn(async move {
             loop {
-                match this.recv_message(&tx).await {
+                task::sleep(STATE_SAVE_INTERVAL).await;
+
+                if let Err(e) = this.save_state() {
+                    info!("save state fail, {}", e);
+                }
+            }
+        }))
+    }
+
+    fn start_maintenance(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        self.spawn(async move {
+            loop {
+                task::sleep(MAINTENANCE_INTERVAL).await;
+
+                let outs = match this.core.tick(Instant::now()) {
+                    Ok(outs) => outs,
+                    Err(e) => {
+                        debug!("maintenance fail, {}", e);
+                        continue;
+                    }
+                };
+                for out in outs {
+                    if let Output::Send { buf, addr } = out {
+                        if let Err(e) = this.send(&buf, &addr).await {
+                            debug!("maintenance send to {} fail, {}", addr, e);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn start_crawler(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        self.spawn(async move {
+            while let Ok(first) = this.crawl.1.recv().await {
+                // what queued up meanwhile goes in the same batch.
+                let mut nodes = vec![first];
+                while nodes.len() < BATCH_SIZE_MAX {
+                    match this.crawl.1.try_recv() {
+                        Ok(x) => nodes.push(x),
+                        Err(_) => break,
+                    }
+                }
+                this.limiter.acquire(nodes.len()).await;
+
+                let now = Instant::now();
+                let mut msgs = Vec::with_capacity(nodes.len());
+                for (node, identity) in nodes {
+                    match this.core.find_node(node.addr, identity, &node.id, now) {
+                        Ok(buf) => msgs.push((buf, node.addr)),
+                        Err(e) => debug!("crawl {} fail, {}", node.addr, e),
+                    }
+                }
+                this.send_batch(None, &msgs).await;
+            }
+        })
+    }
+
+    fn start_message_handler(&self, i: usize, tx: Sender<Message>) -> JoinHandle<()> {
+        let this = self.clone();
+
+        self.spawn(async move {
+            let mut bufs = vec![vec![0; BUFFER_SIZE_MAX]; BATCH_SIZE_MAX];
+            loop {
+                match this.recv_messages(i, &mut bufs, &tx).await {
                     Ok(_) => {}
                     Err(e) => {
                         debug!("recv_message fail, {}", e);
                     }
                 }
             }
-        });
+        })
     }
 
-    async fn recv_message(&mut self, tx: &Sender<Message>) -> Result<()> {
-        let mut buf = [0; BUFFER_SIZE_MAX];
-        let socket = (*self.socket)
-            .as_ref()
-            .ok_or(Error::Other("invalid udp socket".to_string()))?;
-        let (n, from) = socket.recv_from(&mut buf).await?;
-
-        debug!("recv message {} bytes, from {}", n, from);
-
-        // unpack bencode.
-        let c = bencode::from_bytes(&buf[..n])?;
-        let m = c
-            .dict()?
-            .get(b"y".as_ref())
-            .ok_or(Error::DictNotFound("y".to_string()))?;
-
-        match m.string()? {
-            "q" => self.on_query(&c, &from, tx).await,
-            "r" => self.on_reply(&c, &from).await,
-            "e" => self.on_error(&c, &from),
-            _ => Err(Error::Other("skip other request packet".to_string())),
+    // receive a batch of datagrams on transport i and answer them through it.
+    async fn recv_messages(
+        &self,
+        i: usize,
+        bufs: &mut [Vec<u8>],
+        tx: &Sender<Message>,
+    ) -> Result<()> {
+        let received = self.transports[i].recv_batch(bufs).await?;
+        let now = Instant::now();
+
+        let mut replies = Vec::new();
+        for (buf, (n, from)) in bufs.iter().zip(received) {
+            debug!("recv message {} bytes, from {}", n, from);
+
+            let outs = match self.core.handle(&buf[..n], &from, now) {
+                Ok(outs) => outs,
+                Err(e) => {
+                    debug!("handle message from {} fail, {}", from, e);
+                    continue;
+                }
+            };
+            for out in outs {
+                match out {
+                    Output::Send { buf, addr } => replies.push((buf, addr)),
+                    Output::Crawl { node, .. } if !self.reachable(&node.addr) => {}
+                    Output::Crawl { node, identity } => {
+                        if self.crawl.0.try_send((node, identity)).is_err() {
+                            self.core.count(|s| s.find_node_suppressed += 1);
+                        }
+                    }
+                    Output::Event(event) => {
+                        self.publish(&event);
+                        let msg = match event {
+                            DhtEvent::AnnouncePeer { msg, .. } => msg,
+                            _ => continue,
+                        };
+
+                        // never wait for the receiver, the rest of the batch
+                        // and its replies would wait with it.
+                        match tx.try_send(msg) {
+                            Ok(()) => self.core.count(|s| s.announces += 1),
+                            Err(TrySendError::Full(_)) => {
+                                debug!("channel is full, skip.");
+                                self.core.count(|s| s.announces_dropped += 1);
+                            }
+                            Err(TrySendError::Closed(_)) => {
+                                debug!("channel is closed, skip.");
+                                self.core.count(|s| s.announces_dropped += 1);
+                            }
+                        }
+                    }
+                }
+            }
         }
+
+        self.send_batch(Some(i), &replies).await;
+        Ok(())
+    }
+
+    // the events of kinds from now on, in a channel as large as the announce
+    // channel. events finding it full are lost, dropping the receiver
+    // ends the subscription.
+    pub fn subscribe(&self, kinds: &[EventKind]) -> Receiver<DhtEvent> {
+        let (tx, rx) = channel::bounded(self.peers.max(1));
+        let kinds = kinds.to_vec();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber { kinds, tx });
+        rx
     }
 
-    fn on_error(&self, v: &Value, addr: &SocketAddr) -> Result<()> {
-        let e = v
-            .dict()?
-            .get(b"e".as_ref())
-            .ok_or(Error::DictNotFound("e".to_string()))?;
+    // hand event to the subscribers of its kind.
+    fn publish(&self, event: &DhtEvent) {
+        let kind = event.kind();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| !s.tx.is_closed());
 
-        let a = e.list()?;
-        if a.len() != 2 {
-            return Err(Error::Other("invalid dht error list field".to_string()));
+        for s in subscribers.iter().filter(|s| s.kinds.contains(&kind)) {
+            if s.tx.try_send(event.clone()).is_err() {
+                self.core.count(|s| s.events_dropped += 1);
+            }
         }
+    }
+
+    // iterative lookup of the peers of infohash, peers are streamed as the
+    // nodes close to infohash answer. the stream ends with the lookup or
+    // the DHT.
+    pub fn get_peers(&self, infohash: &[u8]) -> impl Stream<Item = SocketAddr> {
+        let (tx, rx) = channel::unbounded();
+        let this = self.clone();
+        let infohash = infohash.to_vec();
+
+        self.spawn(async move {
+            this.lookup(&infohash, Some(&tx)).await;
+        });
+        rx
+    }
 
-        // decode error describe.
-        let code = a[0].integer()?;
-        let desc = a[1].string()?;
+    // announce that we are a peer of infohash on port, or on the source port of
+    // the packets with implied_port. the announce is repeated every announce_interval
+    // until the handle is dropped or the DHT stops.
+    pub fn announce(&self, infohash: &[u8], port: u16, implied_port: bool) -> AnnounceHandle {
+        let (cancel, cancelled) = channel::bounded::<()>(1);
+        let this = self.clone();
+        let infohash = infohash.to_vec();
 
-        debug!("on_error {} code: {}, description: {}", addr, code, desc);
+        self.spawn(async move {
+            loop {
+                let lookup = this.lookup(&infohash, None).await;
+                if cancelled.is_closed() {
+                    break;
+                }
 
-        Ok(())
+                let n = this
+                    .store(&lookup, |token| QueryKind::AnnouncePeer {
+                        info_hash: infohash.clone(),
+                        port: Some(i64::from(port)),
+                        implied_port: Some(i64::from(implied_port)),
+                        token,
+                        seed: false,
+                    })
+                    .await;
+                debug!("announce {} to {} nodes.", to_hex(&infohash), n);
+
+                // the timeout means no cancel yet.
+                if future::timeout(this.announce_interval, cancelled.recv())
+                    .await
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+            debug!("announce {} leave.", to_hex(&infohash));
+        });
+
+        AnnounceHandle { _cancel: cancel }
     }
 
-    async fn on_query(&self, v: &Value, addr: &SocketAddr, tx: &Sender<Message>) -> Result<()> {
-        // do check. is exist of the "t" field?
-        v.dict()?
-            .get(b"t".as_ref())
-            .ok_or(Error::Other("query not found 't' field".to_string()))?;
+    // estimate the seeders and leechers of infohash by merging the BEP 33
+    // bloom filters of the nodes close to it.
+    pub async fn scrape(&self, infohash: &[u8]) -> Scrape {
+        let mut seeds = BloomFilter::new();
+        let mut leechers = BloomFilter::new();
+        let q = QueryKind::GetPeers {
+            info_hash: infohash.to_vec(),
+            scrape: true,
+        };
 
-        let q = v
-            .dict()?
-            .get(b"q".as_ref())
-            .ok_or(Error::Other("query not found 'q' field".to_string()))?
-            .string()?;
+        self.run_lookup(infohash, q, |r| {
+            if let Some(x) = &r.seeds {
+                seeds.merge(x);
+            }
+            if let Some(x) = &r.leechers {
+                leechers.merge(x);
+            }
+            Ok(false)
+        })
+        .await;
 
-        match q {
-            "get_peers" => self.on_get_peers(v, addr).await,
-            "announce_peer" => self.on_announce_peer(v, addr, tx).await,
-            _ => Ok(()),
+        Scrape {
+            seeders: seeds.estimate().round() as u64,
+            leechers: leechers.estimate().round() as u64,
         }
     }
 
-    async fn on_reply(&mut self, v: &Value, addr: &SocketAddr) -> Result<()> {
-        let r = v
-            .dict()?
-            .get(b"r".as_ref())
-            .ok_or(Error::DictNotFound("r".to_string()))?;
+    // get the immutable item of target, checked against target.
+    pub async fn get_immutable(&self, target: &[u8]) -> Option<Value> {
+        let mut res = None;
 
-        let s = r
-            .dict()?
-            .get(b"nodes".as_ref())
-            .ok_or(Error::DictNotFound("nodes".to_string()))?
-            .bytes()?;
+        self.run_lookup(target, get_query(target), |r| {
+            if let Some(v) = &r.v {
+                if immutable_target(v)? == target {
+                    res = Some(v.clone());
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+        .await;
+        res
+    }
 
-        let nodes = decode_nodes(s)?;
-        debug!("on_reply {} decode {} nodes.", addr, nodes.len());
+    // get the mutable item of public key k and salt, the highest seq wins.
+    pub async fn get_mutable(&self, k: &[u8], salt: &[u8]) -> Option<MutableItem> {
+        let mut res: Option<MutableItem> = None;
+        let target = mutable_target(k, salt);
 
-        for node in nodes {
-            if !self.limiter.allow() {
-                continue;
+        self.run_lookup(&target, get_query(&target), |r| {
+            if let Some(item) = r.mutable_item(salt) {
+                let newer = res.as_ref().is_none_or(|x| item.seq > x.seq);
+                if item.k == k && newer && item.verify().is_ok() {
+                    res = Some(item);
+                }
             }
-            self.find_node(node.addr, &node.id).await?;
-        }
+            Ok(false)
+        })
+        .await;
+        res
+    }
 
-        Ok(())
+    // store v on the nodes closest to its target, returns the number of nodes
+    // which acknowledged.
+    pub async fn put_immutable(&self, v: Value) -> Result<usize> {
+        check_value(&v)?;
+        let target = immutable_target(&v)?;
+
+        let lookup = self
+            .run_lookup(&target, get_query(&target), |_| Ok(false))
+            .await;
+
+        let item = Item::Immutable(v);
+        let n = self
+            .store(&lookup, |token| QueryKind::Put {
+                token,
+                item: item.clone(),
+                cas: None,
+            })
+            .await;
+        Ok(n)
     }
 
-    async fn on_get_peers(&self, v: &Value, addr: &SocketAddr) -> Result<()> {
-        let tid = v
-            .dict()?
-            .get(b"t".as_ref())
-            .ok_or(Error::DictNotFound("t".to_string()))?
-            .bytes()?;
-
-        let a = v
-            .dict()?
-            .get(b"a".as_ref())
-            .ok_or(Error::DictNotFound("a".to_string()))?;
-
-        let id = a
-            .dict()?
-            .get(b"id".as_ref())
-            .ok_or(Error::DictNotFound("id".to_string()))?
-            .bytes()?;
-
-        let r = bencode::map!(
-            b"id".to_vec() => Value::from(neighbor_id(id, self.local_id.as_ref())),
-            b"nodes".to_vec() => Value::from(""),
-            b"token".to_vec() => Value::from(self.make_token(addr))
-        );
+    // store a signed item on the nodes closest to its target, cas is the seq
+    // expected to be replaced. returns the number of nodes which acknowledged.
+    pub async fn put_mutable(&self, item: &MutableItem, cas: Option<i64>) -> Result<usize> {
+        item.verify()?;
+        let target = item.target();
+
+        let lookup = self
+            .run_lookup(&target, get_query(&target), |_| Ok(false))
+            .await;
+
+        let item = Item::Mutable(item.clone());
+        let n = self
+            .store(&lookup, |token| QueryKind::Put {
+                token,
+                item: item.clone(),
+                cas,
+            })
+            .await;
+        Ok(n)
+    }
 
-        let buf = self.make_reply(tid, r)?;
-        if let Some(socket) = &*self.socket {
-            socket.send_to(&buf, addr).await?;
+    // ask the node at addr for a sample of the infohashes it stores (BEP 51),
+    // the answer is published as a SampleInfohashes event.
+    pub async fn sample_infohashes(&self, addr: SocketAddr, target: &[u8]) -> Result<usize> {
+        let identity = closest_identity(self.core.identities(), target);
+        let id = self.core.identities()[identity].id();
+        let q = QueryKind::SampleInfohashes {
+            target: target.to_vec(),
         };
+        self.send_query(addr, identity, &id, q, None).await
+    }
 
-        Ok(())
+    // send the query made with the token of each of the closest nodes of a
+    // finished lookup, returns the number of nodes which acknowledged.
+    async fn store<F>(&self, lookup: &Lookup, make_query: F) -> usize
+    where
+        F: Fn(Vec<u8>) -> QueryKind,
+    {
+        let identity = closest_identity(self.core.identities(), lookup.target());
+        let (tx, rx) = channel::unbounded();
+
+        let mut sent = 0;
+        for (node, token) in lookup.closest() {
+            let token = match token {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let id = self.core.identities()[identity].id();
+            let q = make_query(token.to_vec());
+
+            match self
+                .send_query(node.addr, identity, &id, q, Some(tx.clone()))
+                .await
+            {
+                Ok(_) => sent += 1,
+                Err(e) => debug!("store {} fail, {}", node.addr, e),
+            }
+        }
+
+        let mut acked = 0;
+        while acked < sent {
+            match future::timeout(QUERY_TIMEOUT, rx.recv()).await {
+                Ok(Ok(_)) => acked += 1,
+                _ => break,
+            }
+        }
+        acked
     }
 
-    async fn on_announce_peer(
-        &self,
-        v: &Value,
-        addr: &SocketAddr,
-        tx: &Sender<Message>,
-    ) -> Result<()> {
-        let a = v
-            .dict()?
-            .get(b"a".as_ref())
-            .ok_or(Error::DictNotFound("a".to_string()))?;
-
-        let token = a
-            .dict()?
-            .get(b"token".as_ref())
-            .ok_or(Error::DictNotFound("token".to_string()))?
-            .bytes()?;
-
-        if !self.is_valid_token(token, addr) {
-            return Err(Error::Other("announce peers invalid token".to_string()));
+    // run a get_peers lookup of infohash until the closest nodes answered.
+    async fn lookup(&self, infohash: &[u8], peers: Option<&Sender<SocketAddr>>) -> Lookup {
+        let mut seen = HashSet::new();
+        let q = QueryKind::GetPeers {
+            info_hash: infohash.to_vec(),
+            scrape: false,
+        };
+
+        let lookup = self
+            .run_lookup(infohash, q, |r| {
+                for &peer in r.values.iter() {
+                    if !seen.insert(peer) {
+                        continue;
+                    }
+
+                    // the caller lost interest.
+                    if let Some(Err(_)) = peers.map(|x| x.try_send(peer)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            })
+            .await;
+
+        debug!("lookup {} done, {} peers.", to_hex(infohash), seen.len());
+        lookup
+    }
+
+    // iterative lookup of target with query q, on_reply sees every response
+    // and returns true to finish the lookup early.
+    async fn run_lookup<F>(&self, target: &[u8], q: QueryKind, mut on_reply: F) -> Lookup
+    where
+        F: FnMut(&Response) -> Result<bool>,
+    {
+        let identity = closest_identity(self.core.identities(), target);
+        let mut lookup = self.lookup_seeds(target).await;
+        let (tx, rx) = channel::unbounded();
+
+        loop {
+            let now = Instant::now();
+            lookup.expire(now);
+
+            for node in lookup.next(now) {
+                let id = self.core.identities()[identity].id();
+                let res = self
+                    .send_query(node.addr, identity, &id, q.clone(), Some(tx.clone()))
+                    .await;
+                if let Err(e) = res {
+                    debug!("lookup query {} fail, {}", node.addr, e);
+                    lookup.on_failure(&node.addr);
+                }
+            }
+
+            if lookup.is_done() {
+                break;
+            }
+
+            let reply = match future::timeout(QUERY_TIMEOUT, rx.recv()).await {
+                Ok(Ok(reply)) => reply,
+                _ => continue,
+            };
+
+            let r = &reply.r;
+            let nodes = r
+                .nodes
+                .iter()
+                .filter(|x| self.reachable(&x.addr))
+                .cloned()
+                .collect();
+            lookup.on_response(&reply.addr, &r.id, r.token.as_deref(), nodes);
+
+            match on_reply(r) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => {
+                    debug!("lookup reply {} fail, {}", reply.addr, e);
+                    lookup.on_failure(&reply.addr);
+                }
+            }
         }
 
-        if tx.is_full() {
-            info!("channel is full, skip.");
-        } else {
-            let ac = self.summarize(v, addr)?;
-            tx.send(ac).await.map_err(|e| Error::Send(e.into_inner()))?;
+        lookup
+    }
+
+    // the lookup starts from the known nodes closest to target,
+    // or from the bootstrap nodes without any.
+    async fn lookup_seeds(&self, target: &[u8]) -> Lookup {
+        let mut lookup = Lookup::new(target);
+        let mut empty = true;
+
+        for identity in self.core.identities() {
+            let table = identity.table.lock().unwrap();
+            for e in table.closest_where(target, K, |x| self.reachable(x)) {
+                lookup.add(Node {
+                    id: e.id,
+                    addr: e.addr,
+                });
+                empty = false;
+            }
         }
 
-        Ok(())
+        if empty {
+            for seed in self.bootstrap.iter() {
+                match seed.as_str().to_socket_addrs().await {
+                    Ok(addrs) => addrs
+                        .filter(|x| self.reachable(x))
+                        .for_each(|addr| lookup.add_addr(addr)),
+                    Err(e) => debug!("resolve {} fail, {}", seed, e),
+                }
+            }
+        }
+
+        lookup
     }
 
-    fn summarize(&self, v: &Value, addr: &SocketAddr) -> Result<Message> {
-        let a = v
-            .dict()?
-            .get(b"a".as_ref())
-            .ok_or(Error::DictNotFound("a".to_string()))?
-            .dict()?;
-
-        let hash = a
-            .get(b"info_hash".as_ref())
-            .ok_or(Error::DictNotFound("info_hash".to_string()))?
-            .bytes()?;
-
-        // There is an optional argument called implied_port which value is either 0 or 1. If it is
-        // present and non-zero, the port argument should be ignored and the source port of the UDP
-        // packet should be used as the peer's port instead.
-        let mut port = addr.port();
-        if let Some(Value::Integer(0)) = a.get(b"implied_port".as_ref()) {
-            port = a
-                .get(b"port".as_ref())
-                .ok_or(Error::DictNotFound("port".to_string()))?
-                .integer()? as u16;
+    async fn find_node<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        identity: usize,
+        target_id: &[u8],
+    ) -> Result<usize> {
+        let addr = addr
+            .to_socket_addrs()
+            .await?
+            .next()
+            .ok_or_else(|| Error::Other("no address resolved".to_string()))?;
+
+        let buf = self
+            .core
+            .find_node(addr, identity, target_id, Instant::now())?;
+        self.send(&buf, &addr).await
+    }
+
+    // send a query on behalf of identity with id, the reply goes to reply when set.
+    async fn send_query(
+        &self,
+        addr: SocketAddr,
+        identity: usize,
+        id: &[u8],
+        q: QueryKind,
+        reply: Option<Sender<Reply>>,
+    ) -> Result<usize> {
+        let buf = self
+            .core
+            .query(addr, identity, id, q, reply, Instant::now())?;
+        self.send(&buf, &addr).await
+    }
+
+    // whether addr is of the family of the transports.
+    fn reachable(&self, addr: &SocketAddr) -> bool {
+        addr.is_ipv6() == self.ipv6
+    }
+
+    async fn send(&self, buf: &[u8], addr: &SocketAddr) -> Result<usize> {
+        if self.transports.is_empty() {
+            return Ok(0);
         }
 
-        Ok(Message {
-            peer: SocketAddr::new(addr.ip(), port),
-            infohash: hash.to_vec(),
-        })
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.transports.len();
+        self.send_on(i, buf, addr).await
     }
 
-    async fn find_node<A: ToSocketAddrs>(&self, addr: A, target_id: &[u8]) -> Result<usize> {
-        let a = bencode::map!(
-            b"id".to_vec() => Value::from(neighbor_id(target_id, self.local_id.as_ref())),
-            b"target".to_vec() => Value::from(rand_infohash_key())
-        );
+    // send msgs through transport i, or the next transport in turn. the ones
+    // failing are counted and skipped, returns how many were sent.
+    async fn send_batch(&self, i: Option<usize>, msgs: &[Datagram]) -> usize {
+        if self.transports.is_empty() || msgs.is_empty() {
+            return 0;
+        }
 
-        let mut n = 0;
-        let buf = self.make_query(&rand_transation_id(), b"find_node", a)?;
-        if let Some(socket) = &*self.socket {
-            n = socket.send_to(&buf, addr).await?;
+        let i =
+            i.unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed) % self.transports.len());
+        let (mut sent, mut bytes, mut failed) = (0, 0, 0);
+        for ((_, addr), res) in msgs.iter().zip(self.transports[i].send_batch(msgs).await) {
+            match res {
+                Ok(n) => {
+                    sent += 1;
+                    bytes += n;
+                }
+                Err(e) => {
+                    debug!("send to {} fail, {}", addr, e);
+                    failed += 1;
+                }
+            }
+        }
+        self.core.count(|s| {
+            s.packets_out += sent as u64;
+            s.bytes_out += bytes as u64;
+            s.sends_failed += failed;
+        });
+        sent
+    }
+
+    async fn send_on(&self, i: usize, buf: &[u8], addr: &SocketAddr) -> Result<usize> {
+        let n = match self.transports[i].send_to(buf, *addr).await {
+            Ok(n) => n,
+            Err(e) => {
+                self.core.count(|s| s.sends_failed += 1);
+                return Err(e.into());
+            }
         };
+        self.core.count(|s| {
+            s.packets_out += 1;
+            s.bytes_out += n as u64;
+        });
         Ok(n)
     }
+}
 
-    fn make_query(&self, tid: &[u8], qr: &[u8], a: HashMap<Vec<u8>, Value>) -> Result<Vec<u8>> {
-        let m = bencode::map!(
-            b"t".to_vec() => Value::from(tid),
-            b"y".to_vec() => Value::from(b"q".as_ref()),
-            b"q".to_vec() => Value::from(qr),
-            b"a".to_vec() => Value::from(a)
-        );
-        bencode::to_bytes(&Value::from(m)).map_err(crate::Error::from)
+// the crawl queue size, a second of backlog at most, or the nodes of a
+// full batch of replies without a rate.
+fn crawl_backlog(config: &Config) -> usize {
+    match config.friends {
+        0 => BATCH_SIZE_MAX * K,
+        x => x,
     }
+}
 
-    fn make_reply(&self, tid: &[u8], r: HashMap<Vec<u8>, Value>) -> Result<Vec<u8>> {
-        let m = bencode::map!(
-            b"t".to_vec() => Value::from(tid),
-            b"y".to_vec() => Value::from(b"r".as_ref()),
-            b"r".to_vec() => Value::from(r)
-        );
-        bencode::to_bytes(&Value::from(m)).map_err(crate::Error::from)
+// BEP 44 get query of target.
+fn get_query(target: &[u8]) -> QueryKind {
+    QueryKind::Get {
+        target: target.to_vec(),
+        seq: None,
     }
+}
 
-    fn make_token(&self, addr: &SocketAddr) -> Vec<u8> {
-        let mut m = sha1::Sha1::new();
-        m.update(addr.to_string().as_bytes());
-        m.update(&self.secret);
-        m.digest().bytes().to_vec()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryNetwork;
+
+    // a memory network, the addresses of nodes a and b, and config
+    // bootstrapping from a.
+    fn setup(config: Config) -> (MemoryNetwork, SocketAddr, SocketAddr, Config) {
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let config = Config {
+            bootstrap: vec![a.to_string()],
+            ..config
+        };
+        (MemoryNetwork::new(), a, b, config)
     }
 
-    fn is_valid_token(&self, token: &[u8], addr: &SocketAddr) -> bool {
-        token == self.make_token(addr).as_slice()
+    // wait for f to hold, panics after 10 seconds.
+    async fn until<F: Fn() -> bool>(f: F) {
+        let wait = async {
+            while !f() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        future::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_announce_in_memory() {
+        let (network, a, b, config) = setup(Config::default());
+
+        task::block_on(async {
+            let mut node = DHT::new(&config);
+            let (_a, rx) = node.run_on(Box::new(network.bind(a)));
+            let events = node.subscribe(&[EventKind::AnnouncePeer]);
+            let mut dht = DHT::new(&config);
+            let (_b, _) = dht.run_on(Box::new(network.bind(b)));
+
+            let _handle = dht.announce(&[7; 20], 5555, false);
+
+            let msg = future::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.peer, SocketAddr::new(b.ip(), 5555));
+            assert_eq!(msg.infohash, vec![7; 20]);
+
+            // the subscriber saw the announce and none of the other kinds.
+            let event = events.try_recv().unwrap();
+            assert_eq!(event.kind(), EventKind::AnnouncePeer);
+            assert_eq!(event.source().addr, b);
+            assert!(events.try_recv().is_err());
+
+            // the announce is counted once it's in the channel.
+            until(|| node.stats().announces == 1).await;
+            let stats = node.stats();
+            assert_eq!(stats.queries["announce_peer"], 1);
+            assert!(stats.packets_out > 0 && stats.bytes_in > 0);
+        });
+    }
+
+    #[test]
+    fn test_announce_receiver_dropped() {
+        let (network, a, b, config) = setup(Config::default());
+
+        task::block_on(async {
+            let mut node = DHT::new(&config);
+            let (_a, rx) = node.run_on(Box::new(network.bind(a)));
+            drop(rx);
+            let mut dht = DHT::new(&config);
+            let (_b, _) = dht.run_on(Box::new(network.bind(b)));
+
+            // the receive loop outlives the receiver, every announce is dropped.
+            for n in 1..=2 {
+                let _handle = dht.announce(&[n as u8; 20], 5555, false);
+                until(|| node.stats().announces_dropped == n).await;
+            }
+            assert_eq!(node.stats().announces, 0);
+        });
+    }
+
+    #[test]
+    fn test_crawl_unlimited() {
+        let (network, a, b, config) = setup(Config {
+            friends: 0,
+            ..Config::default()
+        });
+
+        task::block_on(async {
+            // a knows a full bucket of nodes, the find_node reply carries them all.
+            let mut node = DHT::new(&config);
+            let wall = SystemTime::now();
+            for i in 0..K as u8 {
+                let addr = SocketAddr::from(([10, 0, 1, i], 6881));
+                let mut table = node.core.identities()[0].table.lock().unwrap();
+                table.insert(&rand_infohash_key(), addr, wall);
+            }
+            let (_a, _) = node.run_on(Box::new(network.bind(a)));
+            let mut dht = DHT::new(&config);
+            let (_b, _) = dht.run_on(Box::new(network.bind(b)));
+
+            until(|| dht.stats().replies > 0).await;
+            // the reply is counted before its nodes are queued.
+            task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(dht.stats().find_node_suppressed, 0);
+        });
+    }
+
+    #[test]
+    fn test_save_state() {
+        let path = std::env::temp_dir().join(format!("btsniffer-{}.json", std::process::id()));
+        let config = Config {
+            state: Some(path.clone()),
+            ..Config::default()
+        };
+        let dht = DHT::new(&config);
+
+        let good = SocketAddr::from(([10, 0, 1, 1], 6881));
+        let bad = SocketAddr::from(([10, 0, 1, 2], 6881));
+        let mut table = dht.core.identities()[0].table.lock().unwrap();
+        table.insert(&rand_infohash_key(), good, SystemTime::now());
+        table.insert(&rand_infohash_key(), bad, SystemTime::now());
+        table.on_failure(&bad);
+        table.on_failure(&bad);
+        drop(table);
+
+        dht.save_state().unwrap();
+        let state = State::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let nodes = &state.identities[0].nodes;
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].addr, good);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (network, a, b, config) = setup(Config::default());
+
+        task::block_on(async {
+            let (handle, rx) = DHT::new(&config).run_on(Box::new(network.bind(a)));
+            future::timeout(Duration::from_secs(5), handle.shutdown())
+                .await
+                .unwrap();
+
+            // the receive loop is gone, so is the announce channel.
+            let mut dht = DHT::new(&config);
+            let (_b, _) = dht.run_on(Box::new(network.bind(b)));
+            let _announce = dht.announce(&[7; 20], 5555, false);
+            assert!(rx.recv().await.is_err());
+        });
     }
 }
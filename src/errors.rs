@@ -1,3 +1,5 @@
+use crate::item::ItemError;
+use crate::krpc::ParseError;
 use crate::Message;
 use async_std::io::Error as AsyncIoError;
 use bencode::Error as BencodeError;
@@ -11,6 +13,15 @@ pub enum Error {
     #[error(transparent)]
     Bencode(#[from] BencodeError),
 
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Item(#[from] ItemError),
+
+    #[error(transparent)]
+    Krpc(#[from] ParseError),
+
     #[error("send fail, message: {0:?}")]
     Send(Message),
 
@@ -0,0 +1,102 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use crate::client::ClientVersion;
+use crate::Message;
+
+// the kinds of DhtEvent, to subscribe to some of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    AnnouncePeer,
+    GetPeersQuery,
+    NodeDiscovered,
+    NodeDropped,
+    SampleInfohashes,
+    ErrorReceived,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::AnnouncePeer,
+        EventKind::GetPeersQuery,
+        EventKind::NodeDiscovered,
+        EventKind::NodeDropped,
+        EventKind::SampleInfohashes,
+        EventKind::ErrorReceived,
+    ];
+}
+
+// the remote node an event is about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    // wall clock time of the event.
+    pub time: SystemTime,
+    // none for errors, they carry no node id.
+    pub id: Option<Vec<u8>>,
+    // the client version the node sent, the top level 'v' of its message.
+    pub v: Option<Vec<u8>>,
+    // v parsed, none without v or when it's not a client code and version.
+    pub client: Option<ClientVersion>,
+    pub addr: SocketAddr,
+}
+
+// what the DHT saw happen, see DHT::subscribe.
+#[derive(Clone, Debug)]
+pub enum DhtEvent {
+    // a peer announced itself with a valid token.
+    AnnouncePeer {
+        source: Source,
+        msg: Message,
+        seed: bool,
+    },
+    // a node looked for the peers of info_hash.
+    GetPeersQuery {
+        source: Source,
+        info_hash: Vec<u8>,
+    },
+    // a node answered us and joined a routing table.
+    NodeDiscovered {
+        source: Source,
+    },
+    // a node was replaced in a routing table, its time is when it was last seen.
+    NodeDropped {
+        source: Source,
+    },
+    // a node answered with a sample of the infohashes it stores (BEP 51).
+    SampleInfohashes {
+        source: Source,
+        samples: Vec<Vec<u8>>,
+        num: Option<i64>,
+        interval: Option<i64>,
+    },
+    // a node answered one of our queries with an error.
+    ErrorReceived {
+        source: Source,
+        code: i64,
+        msg: String,
+    },
+}
+
+impl DhtEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            DhtEvent::AnnouncePeer { .. } => EventKind::AnnouncePeer,
+            DhtEvent::GetPeersQuery { .. } => EventKind::GetPeersQuery,
+            DhtEvent::NodeDiscovered { .. } => EventKind::NodeDiscovered,
+            DhtEvent::NodeDropped { .. } => EventKind::NodeDropped,
+            DhtEvent::SampleInfohashes { .. } => EventKind::SampleInfohashes,
+            DhtEvent::ErrorReceived { .. } => EventKind::ErrorReceived,
+        }
+    }
+
+    pub fn source(&self) -> &Source {
+        match self {
+            DhtEvent::AnnouncePeer { source, .. }
+            | DhtEvent::GetPeersQuery { source, .. }
+            | DhtEvent::NodeDiscovered { source }
+            | DhtEvent::NodeDropped { source }
+            | DhtEvent::SampleInfohashes { source, .. }
+            | DhtEvent::ErrorReceived { source, .. } => source,
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::routing::RoutingTable;
+use crate::util::{distance, rand_infohash_key};
+
+// a virtual node on this instance, with its own routing table and token secret.
+// the node id is the local id of the routing table.
+#[derive(Debug)]
+pub struct Identity {
+    pub table: Mutex<RoutingTable>,
+    secret: Vec<u8>,
+}
+
+impl Identity {
+    pub fn new(id: &[u8], enforce_bep42: bool) -> Self {
+        Self {
+            table: Mutex::new(RoutingTable::new(id, enforce_bep42)),
+            secret: rand_infohash_key(),
+        }
+    }
+
+    pub fn id(&self) -> Vec<u8> {
+        self.table.lock().unwrap().local_id().to_vec()
+    }
+
+    pub fn set_id(&self, id: &[u8]) {
+        self.table.lock().unwrap().set_local_id(id);
+    }
+
+    pub fn make_token(&self, addr: &SocketAddr) -> Vec<u8> {
+        let mut m = sha1::Sha1::new();
+        m.update(addr.to_string().as_bytes());
+        m.update(&self.secret);
+        m.digest().bytes().to_vec()
+    }
+
+    pub fn is_valid_token(&self, token: &[u8], addr: &SocketAddr) -> bool {
+        token == self.make_token(addr).as_slice()
+    }
+}
+
+// make n random ids spread evenly over the keyspace, the top 32 bits
+// are evenly spaced from a random start.
+pub fn spread_ids(n: usize) -> Vec<Vec<u8>> {
+    let base = rand_infohash_key();
+    let start = u32::from_be_bytes([base[0], base[1], base[2], base[3]]);
+
+    (0..n as u64)
+        .map(|i| {
+            let step = ((i << 32) / n as u64) as u32;
+            let mut id = rand_infohash_key();
+            id[..4].copy_from_slice(&start.wrapping_add(step).to_be_bytes());
+            id
+        })
+        .collect()
+}
+
+// index of the identity closest to key.
+pub fn closest_identity(identities: &[Identity], key: &[u8]) -> usize {
+    identities
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, x)| distance(&x.id(), key))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(id: &[u8]) -> u32 {
+        u32::from_be_bytes([id[0], id[1], id[2], id[3]])
+    }
+
+    #[test]
+    fn test_spread_ids() {
+        let ids = spread_ids(4);
+        assert_eq!(ids.len(), 4);
+
+        for i in 0..4 {
+            assert_eq!(ids[i].len(), 20);
+            let step = prefix(&ids[(i + 1) % 4]).wrapping_sub(prefix(&ids[i]));
+            assert_eq!(step, 1 << 30);
+        }
+    }
+
+    #[test]
+    fn test_closest_identity() {
+        let identities: Vec<Identity> = [0x00u8, 0x40, 0x80, 0xc0]
+            .iter()
+            .map(|x| {
+                let mut id = vec![0; 20];
+                id[0] = *x;
+                Identity::new(&id, false)
+            })
+            .collect();
+
+        let mut key = vec![0; 20];
+        key[0] = 0x85;
+        assert_eq!(closest_identity(&identities, &key), 2);
+        key[0] = 0x3f;
+        assert_eq!(closest_identity(&identities, &key), 0);
+    }
+
+    #[test]
+    fn test_token() {
+        let identity = Identity::new(&rand_infohash_key(), false);
+        let other = Identity::new(&rand_infohash_key(), false);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+
+        let token = identity.make_token(&addr);
+        assert!(identity.is_valid_token(&token, &addr));
+        assert!(!other.is_valid_token(&token, &addr));
+        assert!(!identity.is_valid_token(&token, &"127.0.0.1:6882".parse().unwrap()));
+    }
+}
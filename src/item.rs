@@ -0,0 +1,234 @@
+use std::convert::TryFrom;
+
+use bencode::Value;
+use ed25519_dalek::{PublicKey, Signature, Signer, Verifier};
+use thiserror::Error as ThisError;
+
+use crate::Result;
+
+pub use ed25519_dalek::Keypair;
+
+// max size of the bencoded value.
+pub const VALUE_SIZE_MAX: usize = 1000;
+// max size of the salt.
+pub const SALT_SIZE_MAX: usize = 64;
+
+// errors of storing an item, see BEP 44.
+#[derive(ThisError, Debug, Clone, PartialEq)]
+pub enum ItemError {
+    #[error("message (v field) too big")]
+    TooBig,
+
+    #[error("invalid signature")]
+    InvalidSignature,
+
+    #[error("salt (salt field) too big")]
+    SaltTooBig,
+
+    #[error("the CAS hash mismatched, re-read value and try again")]
+    CasMismatch,
+
+    #[error("sequence number less than current")]
+    SeqTooLow,
+}
+
+impl ItemError {
+    // KRPC error code.
+    pub fn code(&self) -> i64 {
+        match self {
+            ItemError::TooBig => 205,
+            ItemError::InvalidSignature => 206,
+            ItemError::SaltTooBig => 207,
+            ItemError::CasMismatch => 301,
+            ItemError::SeqTooLow => 302,
+        }
+    }
+}
+
+// target of an immutable item, the sha1 of the bencoded value.
+pub fn immutable_target(v: &Value) -> Result<Vec<u8>> {
+    let mut m = sha1::Sha1::new();
+    m.update(&bencode::to_bytes(v)?);
+    Ok(m.digest().bytes().to_vec())
+}
+
+// target of a mutable item, the sha1 of the public key and the salt.
+pub fn mutable_target(k: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut m = sha1::Sha1::new();
+    m.update(k);
+    m.update(salt);
+    m.digest().bytes().to_vec()
+}
+
+// check the size of the bencoded value.
+pub fn check_value(v: &Value) -> std::result::Result<(), ItemError> {
+    match bencode::to_bytes(v) {
+        Ok(buf) if buf.len() <= VALUE_SIZE_MAX => Ok(()),
+        _ => Err(ItemError::TooBig),
+    }
+}
+
+// stored item.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Immutable(Value),
+    Mutable(MutableItem),
+}
+
+// mutable item, signed by the owner of k.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutableItem {
+    pub k: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub sig: Vec<u8>,
+    pub v: Value,
+}
+
+impl MutableItem {
+    pub fn new(keypair: &Keypair, salt: &[u8], seq: i64, v: Value) -> Result<Self> {
+        let sig = keypair.sign(&signable(salt, seq, &v)?);
+
+        Ok(Self {
+            k: keypair.public.to_bytes().to_vec(),
+            salt: salt.to_vec(),
+            seq,
+            sig: sig.to_bytes().to_vec(),
+            v,
+        })
+    }
+
+    pub fn target(&self) -> Vec<u8> {
+        mutable_target(&self.k, &self.salt)
+    }
+
+    // check the sizes and the signature.
+    pub fn verify(&self) -> std::result::Result<(), ItemError> {
+        check_value(&self.v)?;
+
+        if self.salt.len() > SALT_SIZE_MAX {
+            return Err(ItemError::SaltTooBig);
+        }
+
+        let k = PublicKey::from_bytes(&self.k).map_err(|_| ItemError::InvalidSignature)?;
+        let sig =
+            Signature::try_from(self.sig.as_slice()).map_err(|_| ItemError::InvalidSignature)?;
+        let buf = signable(&self.salt, self.seq, &self.v).map_err(|_| ItemError::TooBig)?;
+
+        k.verify(&buf, &sig)
+            .map_err(|_| ItemError::InvalidSignature)
+    }
+}
+
+// the signed buffer, 4:salt<salt>3:seqi<seq>e1:v<v> without the outer dict.
+fn signable(salt: &[u8], seq: i64, v: &Value) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(b"4:salt");
+        buf.extend_from_slice(&bencode::to_bytes(&Value::from(salt))?);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(&bencode::to_bytes(v)?);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{from_hex, to_hex};
+    use ed25519_dalek::ExpandedSecretKey;
+
+    // test vectors from BEP 44, the secret is an expanded ed25519 key.
+    const SECRET: &str = "e06d3183d14159228433ed599221b80bd0a5ce8352e4bdf0262f76786ef1c74d\
+                          b7e7a9fea2c0eb269d61e3b38e450a22e754941ac78479d6c54e1faf6037881d";
+    const PUBLIC: &str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+
+    fn sign_vector(salt: &[u8]) -> MutableItem {
+        let secret = ExpandedSecretKey::from_bytes(&from_hex(SECRET).unwrap()).unwrap();
+        let public = PublicKey::from_bytes(&from_hex(PUBLIC).unwrap()).unwrap();
+
+        let v = Value::from("Hello World!");
+        let sig = secret.sign(&signable(salt, 1, &v).unwrap(), &public);
+
+        MutableItem {
+            k: public.to_bytes().to_vec(),
+            salt: salt.to_vec(),
+            seq: 1,
+            sig: sig.to_bytes().to_vec(),
+            v,
+        }
+    }
+
+    #[test]
+    fn test_immutable_target() {
+        let v = Value::from("Hello World!");
+        assert_eq!(
+            to_hex(&immutable_target(&v).unwrap()),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+    }
+
+    #[test]
+    fn test_signable() {
+        let v = Value::from("Hello World!");
+        assert_eq!(
+            signable(b"", 1, &v).unwrap(),
+            b"3:seqi1e1:v12:Hello World!".to_vec()
+        );
+        assert_eq!(
+            signable(b"foobar", 1, &v).unwrap(),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_mutable_vectors() {
+        let item = sign_vector(b"");
+        assert_eq!(
+            to_hex(&item.sig),
+            "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+             1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"
+        );
+        assert_eq!(
+            to_hex(&item.target()),
+            "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+        );
+        assert_eq!(item.verify(), Ok(()));
+
+        let item = sign_vector(b"foobar");
+        assert_eq!(
+            to_hex(&item.sig),
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+             df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"
+        );
+        assert_eq!(
+            to_hex(&item.target()),
+            "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+        );
+        assert_eq!(item.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_mutable_item() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let item = MutableItem::new(&keypair, b"salt", 7, Value::from("v")).unwrap();
+        assert_eq!(item.k, keypair.public.to_bytes().to_vec());
+        assert_eq!(item.verify(), Ok(()));
+
+        // tampered value.
+        let mut bad = item.clone();
+        bad.v = Value::from("w");
+        assert_eq!(bad.verify(), Err(ItemError::InvalidSignature));
+
+        let mut bad = item.clone();
+        bad.seq = 8;
+        assert_eq!(bad.verify(), Err(ItemError::InvalidSignature));
+
+        let mut bad = item;
+        bad.salt = vec![0; SALT_SIZE_MAX + 1];
+        assert_eq!(bad.verify(), Err(ItemError::SaltTooBig));
+
+        let big = Value::from(vec![0; VALUE_SIZE_MAX].as_slice());
+        assert_eq!(check_value(&big), Err(ItemError::TooBig));
+    }
+}
@@ -0,0 +1,963 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use bencode::Value;
+use thiserror::Error as ThisError;
+
+use crate::bloom::BloomFilter;
+use crate::item::{Item, MutableItem};
+use crate::node::{
+    decode_addr, decode_nodes, decode_nodes6, encode_addr, encode_nodes, encode_nodes6, Node,
+};
+use crate::Result;
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+// error codes of BEP 5.
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+// the query methods we parse, see QueryKind::method.
+const METHODS: [&str; 7] = [
+    "ping",
+    "find_node",
+    "get_peers",
+    "announce_peer",
+    "sample_infohashes",
+    "get",
+    "put",
+];
+
+// why a packet is not a valid KRPC message.
+#[derive(ThisError, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("invalid bencode")]
+    Bencode,
+
+    #[error("missing '{0}'")]
+    Missing(&'static str),
+
+    #[error("invalid '{0}'")]
+    Invalid(&'static str),
+
+    #[error("unknown message type '{0}'")]
+    UnknownType(String),
+
+    #[error("unknown method '{0}'")]
+    UnknownMethod(String),
+}
+
+impl ParseError {
+    // KRPC error code.
+    pub fn code(&self) -> i64 {
+        match self {
+            ParseError::UnknownMethod(_) => ERROR_METHOD_UNKNOWN,
+            _ => ERROR_PROTOCOL,
+        }
+    }
+}
+
+type ParseResult<T> = std::result::Result<T, ParseError>;
+
+// the transaction id of a query which failed to parse, none for other packets.
+pub fn query_tid(buf: &[u8]) -> Option<Vec<u8>> {
+    let v = bencode::from_bytes(buf).ok()?;
+    let m = v.dict().ok()?;
+
+    if m.get(b"y".as_ref())?.bytes().ok()? != b"q" {
+        return None;
+    }
+    Some(m.get(b"t".as_ref())?.bytes().ok()?.to_vec())
+}
+
+// a KRPC message, see BEP 5.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Krpc {
+    Query {
+        tid: Vec<u8>,
+        id: Vec<u8>,
+        q: QueryKind,
+        // the querier does not answer queries (BEP 43).
+        ro: bool,
+        want: Want,
+    },
+    Response {
+        tid: Vec<u8>,
+        r: Response,
+        // the requestor's address as seen by the responder (BEP 42).
+        ip: Option<SocketAddr>,
+    },
+    Error {
+        tid: Vec<u8>,
+        code: i64,
+        msg: String,
+    },
+}
+
+// the address families of the nodes a querier wants (BEP 32).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Want {
+    pub n4: bool,
+    pub n6: bool,
+}
+
+impl Want {
+    // the families to answer addr with, its own one when it named none.
+    pub fn or_family(self, addr: &SocketAddr) -> Self {
+        if self.n4 || self.n6 {
+            return self;
+        }
+        Self {
+            n4: addr.is_ipv4(),
+            n6: addr.is_ipv6(),
+        }
+    }
+}
+
+// the method of a query and its arguments, except the querier id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryKind {
+    Ping,
+    FindNode {
+        target: Vec<u8>,
+    },
+    GetPeers {
+        info_hash: Vec<u8>,
+        // BEP 33.
+        scrape: bool,
+    },
+    AnnouncePeer {
+        info_hash: Vec<u8>,
+        port: Option<i64>,
+        implied_port: Option<i64>,
+        token: Vec<u8>,
+        // BEP 33.
+        seed: bool,
+    },
+    // BEP 51.
+    SampleInfohashes {
+        target: Vec<u8>,
+    },
+    // BEP 44.
+    Get {
+        target: Vec<u8>,
+        seq: Option<i64>,
+    },
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+}
+
+impl QueryKind {
+    pub fn method(&self) -> &'static str {
+        match self {
+            QueryKind::Ping => "ping",
+            QueryKind::FindNode { .. } => "find_node",
+            QueryKind::GetPeers { .. } => "get_peers",
+            QueryKind::AnnouncePeer { .. } => "announce_peer",
+            QueryKind::SampleInfohashes { .. } => "sample_infohashes",
+            QueryKind::Get { .. } => "get",
+            QueryKind::Put { .. } => "put",
+        }
+    }
+}
+
+// the 'r' dict of a response. responses do not name their method, every
+// field is optional except the responder id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub id: Vec<u8>,
+    // nodes of both families, ipv6 ones travel in 'nodes6'.
+    pub nodes: Vec<Node>,
+    pub token: Option<Vec<u8>>,
+    pub values: Vec<SocketAddr>,
+    // BEP 33.
+    pub seeds: Option<BloomFilter>,
+    pub leechers: Option<BloomFilter>,
+    // BEP 51.
+    pub samples: Option<Vec<Vec<u8>>>,
+    pub interval: Option<i64>,
+    pub num: Option<i64>,
+    // BEP 44.
+    pub v: Option<Value>,
+    pub k: Option<Vec<u8>>,
+    pub sig: Option<Vec<u8>>,
+    pub seq: Option<i64>,
+}
+
+impl Response {
+    pub fn new(id: &[u8]) -> Self {
+        Self {
+            id: id.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    // the mutable item of a get response, none without a value or a key.
+    pub fn mutable_item(&self, salt: &[u8]) -> Option<MutableItem> {
+        Some(MutableItem {
+            k: self.k.clone()?,
+            salt: salt.to_vec(),
+            seq: self.seq?,
+            sig: self.sig.clone()?,
+            v: self.v.clone()?,
+        })
+    }
+}
+
+impl Krpc {
+    pub fn parse(buf: &[u8]) -> ParseResult<Krpc> {
+        let v = bencode::from_bytes(buf).map_err(|_| ParseError::Bencode)?;
+        Krpc::from_value(&v)
+    }
+
+    // the message and the client version of its sender, the top level 'v'
+    // most clients add to every message.
+    pub fn parse_versioned(buf: &[u8]) -> ParseResult<(Krpc, Option<Vec<u8>>)> {
+        let v = bencode::from_bytes(buf).map_err(|_| ParseError::Bencode)?;
+        let version = v
+            .dict()
+            .ok()
+            .and_then(|m| m.get(b"v".as_ref()))
+            .and_then(|x| x.bytes().ok())
+            .map(|x| x.to_vec());
+        Ok((Krpc::from_value(&v)?, version))
+    }
+
+    pub fn from_value(v: &Value) -> ParseResult<Krpc> {
+        let m = v.dict().map_err(|_| ParseError::Invalid("message"))?;
+        let tid = bytes(m, "t")?.to_vec();
+
+        match string(m, "y")? {
+            "q" => {
+                // an unknown method is told apart from missing arguments.
+                let q = string(m, "q")?;
+                if !METHODS.contains(&q) {
+                    return Err(ParseError::UnknownMethod(q.to_string()));
+                }
+
+                let a = dict(m, "a")?;
+                let id = node_id(a, "id")?;
+                let q = parse_query(q, a)?;
+                let ro = opt_integer(m, "ro")? == Some(1);
+                let want = parse_want(a)?;
+                Ok(Krpc::Query {
+                    tid,
+                    id,
+                    q,
+                    ro,
+                    want,
+                })
+            }
+            "r" => {
+                // a malformed 'ip' is only a missed vote.
+                let ip = opt_bytes(m, "ip").ok().flatten().and_then(decode_addr);
+                let r = parse_response(dict(m, "r")?)?;
+                Ok(Krpc::Response { tid, r, ip })
+            }
+            "e" => {
+                let e = get(m, "e")?.list().map_err(|_| ParseError::Invalid("e"))?;
+                let (code, msg) = match e.as_slice() {
+                    [Value::Integer(code), Value::ByteString(msg)] => (*code, msg),
+                    _ => return Err(ParseError::Invalid("e")),
+                };
+
+                let msg = String::from_utf8_lossy(msg).to_string();
+                Ok(Krpc::Error { tid, code, msg })
+            }
+            y => Err(ParseError::UnknownType(y.to_string())),
+        }
+    }
+
+    pub fn tid(&self) -> &[u8] {
+        match self {
+            Krpc::Query { tid, .. } | Krpc::Response { tid, .. } | Krpc::Error { tid, .. } => tid,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bencode::to_bytes(&self.to_value())?)
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut m = Dict::new();
+
+        match self {
+            Krpc::Query {
+                tid,
+                id,
+                q,
+                ro,
+                want,
+            } => {
+                let mut a = encode_query(id, q);
+                let families: Vec<Value> = [(want.n4, "n4"), (want.n6, "n6")]
+                    .iter()
+                    .filter(|(wanted, _)| *wanted)
+                    .map(|(_, x)| Value::from(*x))
+                    .collect();
+                if !families.is_empty() {
+                    a.insert(b"want".to_vec(), Value::from(families));
+                }
+
+                m.insert(b"t".to_vec(), Value::from(tid.as_slice()));
+                m.insert(b"y".to_vec(), Value::from("q"));
+                m.insert(b"q".to_vec(), Value::from(q.method()));
+                m.insert(b"a".to_vec(), Value::from(a));
+                if *ro {
+                    m.insert(b"ro".to_vec(), Value::from(1));
+                }
+            }
+            Krpc::Response { tid, r, ip } => {
+                m.insert(b"t".to_vec(), Value::from(tid.as_slice()));
+                m.insert(b"y".to_vec(), Value::from("r"));
+                m.insert(b"r".to_vec(), Value::from(encode_response(r)));
+                if let Some(ip) = ip {
+                    m.insert(b"ip".to_vec(), Value::from(encode_addr(ip)));
+                }
+            }
+            Krpc::Error { tid, code, msg } => {
+                m.insert(b"t".to_vec(), Value::from(tid.as_slice()));
+                m.insert(b"y".to_vec(), Value::from("e"));
+                let e = vec![Value::from(*code), Value::from(msg.as_str())];
+                m.insert(b"e".to_vec(), Value::from(e));
+            }
+        }
+        Value::from(m)
+    }
+}
+
+// the families in 'want', unknown ones are ignored.
+fn parse_want(a: &Dict) -> ParseResult<Want> {
+    let mut want = Want::default();
+    if let Some(x) = a.get(b"want".as_ref()) {
+        for v in x.list().map_err(|_| ParseError::Invalid("want"))? {
+            match v.bytes().map_err(|_| ParseError::Invalid("want"))? {
+                b"n4" => want.n4 = true,
+                b"n6" => want.n6 = true,
+                _ => {}
+            }
+        }
+    }
+    Ok(want)
+}
+
+fn parse_query(q: &str, a: &Dict) -> ParseResult<QueryKind> {
+    let q = match q {
+        "ping" => QueryKind::Ping,
+        "find_node" => QueryKind::FindNode {
+            target: bytes(a, "target")?.to_vec(),
+        },
+        "get_peers" => QueryKind::GetPeers {
+            info_hash: bytes(a, "info_hash")?.to_vec(),
+            scrape: opt_integer(a, "scrape")? == Some(1),
+        },
+        "announce_peer" => QueryKind::AnnouncePeer {
+            info_hash: bytes(a, "info_hash")?.to_vec(),
+            port: opt_integer(a, "port")?,
+            implied_port: opt_integer(a, "implied_port")?,
+            token: bytes(a, "token")?.to_vec(),
+            seed: opt_integer(a, "seed")? == Some(1),
+        },
+        "sample_infohashes" => QueryKind::SampleInfohashes {
+            target: bytes(a, "target")?.to_vec(),
+        },
+        "get" => QueryKind::Get {
+            target: bytes(a, "target")?.to_vec(),
+            seq: opt_integer(a, "seq")?,
+        },
+        "put" => {
+            let v = get(a, "v")?.clone();
+
+            // items with a public key are mutable.
+            let item = match opt_bytes(a, "k")? {
+                Some(k) => Item::Mutable(MutableItem {
+                    k: k.to_vec(),
+                    salt: opt_bytes(a, "salt")?.unwrap_or_default().to_vec(),
+                    seq: integer(a, "seq")?,
+                    sig: bytes(a, "sig")?.to_vec(),
+                    v,
+                }),
+                None => Item::Immutable(v),
+            };
+
+            QueryKind::Put {
+                token: bytes(a, "token")?.to_vec(),
+                item,
+                cas: opt_integer(a, "cas")?,
+            }
+        }
+        q => return Err(ParseError::UnknownMethod(q.to_string())),
+    };
+    Ok(q)
+}
+
+fn encode_query(id: &[u8], q: &QueryKind) -> Dict {
+    let mut a = Dict::new();
+    a.insert(b"id".to_vec(), Value::from(id));
+
+    let mut put = |k: &str, v: Value| {
+        a.insert(k.as_bytes().to_vec(), v);
+    };
+
+    match q {
+        QueryKind::Ping => {}
+        QueryKind::FindNode { target } => put("target", Value::from(target.as_slice())),
+        QueryKind::GetPeers { info_hash, scrape } => {
+            put("info_hash", Value::from(info_hash.as_slice()));
+            if *scrape {
+                put("scrape", Value::from(1));
+            }
+        }
+        QueryKind::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+            seed,
+        } => {
+            put("info_hash", Value::from(info_hash.as_slice()));
+            put("token", Value::from(token.as_slice()));
+            if let Some(port) = port {
+                put("port", Value::from(*port));
+            }
+            if let Some(implied_port) = implied_port {
+                put("implied_port", Value::from(*implied_port));
+            }
+            if *seed {
+                put("seed", Value::from(1));
+            }
+        }
+        QueryKind::SampleInfohashes { target } => put("target", Value::from(target.as_slice())),
+        QueryKind::Get { target, seq } => {
+            put("target", Value::from(target.as_slice()));
+            if let Some(seq) = seq {
+                put("seq", Value::from(*seq));
+            }
+        }
+        QueryKind::Put { token, item, cas } => {
+            put("token", Value::from(token.as_slice()));
+            match item {
+                Item::Immutable(v) => put("v", v.clone()),
+                Item::Mutable(item) => {
+                    put("k", Value::from(item.k.as_slice()));
+                    put("seq", Value::from(item.seq));
+                    put("sig", Value::from(item.sig.as_slice()));
+                    put("v", item.v.clone());
+                    if !item.salt.is_empty() {
+                        put("salt", Value::from(item.salt.as_slice()));
+                    }
+                }
+            }
+            if let Some(cas) = cas {
+                put("cas", Value::from(*cas));
+            }
+        }
+    }
+    a
+}
+
+fn parse_response(r: &Dict) -> ParseResult<Response> {
+    let mut nodes = match opt_bytes(r, "nodes")? {
+        Some(x) => decode_nodes(x).map_err(|_| ParseError::Invalid("nodes"))?,
+        None => Vec::new(),
+    };
+    if let Some(x) = opt_bytes(r, "nodes6")? {
+        nodes.extend(decode_nodes6(x).map_err(|_| ParseError::Invalid("nodes6"))?);
+    }
+
+    let mut values = Vec::new();
+    if let Some(x) = r.get(b"values".as_ref()) {
+        for v in x.list().map_err(|_| ParseError::Invalid("values"))? {
+            let v = v.bytes().map_err(|_| ParseError::Invalid("values"))?;
+            values.push(decode_addr(v).ok_or(ParseError::Invalid("values"))?);
+        }
+    }
+
+    let filter = |k: &'static str| -> ParseResult<Option<BloomFilter>> {
+        match opt_bytes(r, k)? {
+            Some(x) => Ok(Some(
+                BloomFilter::from_bytes(x).ok_or(ParseError::Invalid(k))?,
+            )),
+            None => Ok(None),
+        }
+    };
+
+    // samples are infohashes back to back.
+    let samples = match opt_bytes(r, "samples")? {
+        Some(x) if x.len() % 20 == 0 => Some(x.chunks(20).map(|x| x.to_vec()).collect()),
+        Some(_) => return Err(ParseError::Invalid("samples")),
+        None => None,
+    };
+
+    Ok(Response {
+        id: node_id(r, "id")?,
+        nodes,
+        token: opt_bytes(r, "token")?.map(|x| x.to_vec()),
+        values,
+        seeds: filter("BFsd")?,
+        leechers: filter("BFpe")?,
+        samples,
+        interval: opt_integer(r, "interval")?,
+        num: opt_integer(r, "num")?,
+        v: r.get(b"v".as_ref()).cloned(),
+        k: opt_bytes(r, "k")?.map(|x| x.to_vec()),
+        sig: opt_bytes(r, "sig")?.map(|x| x.to_vec()),
+        seq: opt_integer(r, "seq")?,
+    })
+}
+
+fn encode_response(r: &Response) -> Dict {
+    let mut m = Dict::new();
+    m.insert(b"id".to_vec(), Value::from(r.id.as_slice()));
+
+    let mut put = |k: &str, v: Value| {
+        m.insert(k.as_bytes().to_vec(), v);
+    };
+
+    let nodes = encode_nodes(&r.nodes);
+    if !nodes.is_empty() {
+        put("nodes", Value::from(nodes));
+    }
+    let nodes6 = encode_nodes6(&r.nodes);
+    if !nodes6.is_empty() {
+        put("nodes6", Value::from(nodes6));
+    }
+    if let Some(token) = &r.token {
+        put("token", Value::from(token.as_slice()));
+    }
+    if !r.values.is_empty() {
+        let values: Vec<Value> = r
+            .values
+            .iter()
+            .map(|x| Value::from(encode_addr(x)))
+            .collect();
+        put("values", Value::from(values));
+    }
+    if let Some(x) = &r.seeds {
+        put("BFsd", Value::from(x.as_bytes()));
+    }
+    if let Some(x) = &r.leechers {
+        put("BFpe", Value::from(x.as_bytes()));
+    }
+    if let Some(samples) = &r.samples {
+        put("samples", Value::from(samples.concat()));
+    }
+    if let Some(x) = r.interval {
+        put("interval", Value::from(x));
+    }
+    if let Some(x) = r.num {
+        put("num", Value::from(x));
+    }
+    if let Some(v) = &r.v {
+        put("v", v.clone());
+    }
+    if let Some(k) = &r.k {
+        put("k", Value::from(k.as_slice()));
+    }
+    if let Some(sig) = &r.sig {
+        put("sig", Value::from(sig.as_slice()));
+    }
+    if let Some(seq) = r.seq {
+        put("seq", Value::from(seq));
+    }
+    m
+}
+
+fn get<'a>(m: &'a Dict, k: &'static str) -> ParseResult<&'a Value> {
+    m.get(k.as_bytes()).ok_or(ParseError::Missing(k))
+}
+
+fn dict<'a>(m: &'a Dict, k: &'static str) -> ParseResult<&'a Dict> {
+    get(m, k)?.dict().map_err(|_| ParseError::Invalid(k))
+}
+
+fn bytes<'a>(m: &'a Dict, k: &'static str) -> ParseResult<&'a [u8]> {
+    get(m, k)?.bytes().map_err(|_| ParseError::Invalid(k))
+}
+
+fn string<'a>(m: &'a Dict, k: &'static str) -> ParseResult<&'a str> {
+    std::str::from_utf8(bytes(m, k)?).map_err(|_| ParseError::Invalid(k))
+}
+
+fn integer(m: &Dict, k: &'static str) -> ParseResult<i64> {
+    get(m, k)?.integer().map_err(|_| ParseError::Invalid(k))
+}
+
+fn opt_bytes<'a>(m: &'a Dict, k: &'static str) -> ParseResult<Option<&'a [u8]>> {
+    match m.contains_key(k.as_bytes()) {
+        true => bytes(m, k).map(Some),
+        false => Ok(None),
+    }
+}
+
+fn opt_integer(m: &Dict, k: &'static str) -> ParseResult<Option<i64>> {
+    match m.contains_key(k.as_bytes()) {
+        true => integer(m, k).map(Some),
+        false => Ok(None),
+    }
+}
+
+// node ids are 20 bytes.
+fn node_id(m: &Dict, k: &'static str) -> ParseResult<Vec<u8>> {
+    match bytes(m, k)? {
+        x if x.len() == 20 => Ok(x.to_vec()),
+        _ => Err(ParseError::Invalid(k)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse a packet of BEP 5, and encode it back to the same bytes.
+    fn check(packet: &[u8], expected: Krpc) {
+        let m = Krpc::parse(packet).unwrap();
+        assert_eq!(m, expected);
+        assert_eq!(m.encode().unwrap(), packet);
+    }
+
+    #[test]
+    fn test_ping() {
+        check(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::Ping,
+            },
+        );
+        check(
+            b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+            Krpc::Response {
+                tid: b"aa".to_vec(),
+                r: Response::new(b"mnopqrstuvwxyz123456"),
+                ip: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let (m, v) = Krpc::parse_versioned(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:LT\x01\x021:y1:qe",
+        )
+        .unwrap();
+        assert_eq!(m.tid(), b"aa");
+        assert_eq!(v, Some(b"LT\x01\x02".to_vec()));
+
+        let (_, v) =
+            Krpc::parse_versioned(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(v, None);
+    }
+
+    #[test]
+    fn test_read_only() {
+        check(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                ro: true,
+                want: Want::default(),
+                id: b"abcdefghij0123456789".to_vec(),
+                q: QueryKind::Ping,
+            },
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        check(
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+            Krpc::Error {
+                tid: b"aa".to_vec(),
+                code: 201,
+                msg: "A Generic Error Ocurred".to_string(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_find_node() {
+        check(
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::FindNode {
+                    target: b"mnopqrstuvwxyz123456".to_vec(),
+                },
+            },
+        );
+
+        // the example's nodes are a placeholder, use one real compact node.
+        check(
+            b"d2:ip6:\x7f\0\0\x01\x1a\xe11:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz123456\x7f\0\0\x01\x1a\xe1e1:t2:aa1:y1:re",
+            Krpc::Response {
+                tid: b"aa".to_vec(),
+                r: Response {
+                    nodes: vec![Node {
+                        id: b"mnopqrstuvwxyz123456".to_vec(),
+                        addr: "127.0.0.1:6881".parse().unwrap(),
+                    }],
+                    ..Response::new(b"0123456789abcdefghij")
+                },
+                ip: Some("127.0.0.1:6881".parse().unwrap()),
+            },
+        );
+    }
+
+    #[test]
+    fn test_want() {
+        check(
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want { n4: true, n6: true },
+                q: QueryKind::FindNode {
+                    target: b"mnopqrstuvwxyz123456".to_vec(),
+                },
+            },
+        );
+
+        // without want, the querier gets its own family.
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6881".parse().unwrap();
+        let own = |addr: &SocketAddr| Want::default().or_family(addr);
+        assert_eq!(
+            own(&v4),
+            Want {
+                n4: true,
+                n6: false
+            }
+        );
+        assert_eq!(
+            own(&v6),
+            Want {
+                n4: false,
+                n6: true
+            }
+        );
+        let n6 = Want {
+            n4: false,
+            n6: true,
+        };
+        assert_eq!(n6.or_family(&v4), n6);
+    }
+
+    #[test]
+    fn test_get_peers() {
+        check(
+            b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::GetPeers {
+                    info_hash: b"mnopqrstuvwxyz123456".to_vec(),
+                    scrape: false,
+                },
+            },
+        );
+        check(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+            Krpc::Response {
+                tid: b"aa".to_vec(),
+                r: Response {
+                    token: Some(b"aoeusnth".to_vec()),
+                    values: vec![
+                        decode_addr(b"axje.u").unwrap(),
+                        decode_addr(b"idhtnm").unwrap(),
+                    ],
+                    ..Response::new(b"abcdefghij0123456789")
+                },
+                ip: None,
+            },
+        );
+
+        // no nodes, only ipv6 ones in nodes6.
+        check(
+            b"d1:rd2:id20:abcdefghij01234567896:nodes638:mnopqrstuvwxyz123456\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe15:token8:aoeusnthe1:t2:aa1:y1:re",
+            Krpc::Response {
+                tid: b"aa".to_vec(),
+                r: Response {
+                    nodes: vec![Node {
+                        id: b"mnopqrstuvwxyz123456".to_vec(),
+                        addr: "[::1]:6881".parse().unwrap(),
+                    }],
+                    token: Some(b"aoeusnth".to_vec()),
+                    ..Response::new(b"abcdefghij0123456789")
+                },
+                ip: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_announce_peer() {
+        check(
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::AnnouncePeer {
+                    info_hash: b"mnopqrstuvwxyz123456".to_vec(),
+                    port: Some(6881),
+                    implied_port: Some(1),
+                    token: b"aoeusnth".to_vec(),
+                    seed: false,
+                },
+            },
+        );
+    }
+
+    #[test]
+    fn test_sample_infohashes() {
+        check(
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::SampleInfohashes {
+                    target: b"mnopqrstuvwxyz123456".to_vec(),
+                },
+            },
+        );
+        check(
+            b"d1:rd2:id20:abcdefghij01234567898:intervali60e3:numi2e7:samples40:mnopqrstuvwxyz123456mnopqrstuvwxyz654321e1:t2:aa1:y1:re",
+            Krpc::Response {
+                tid: b"aa".to_vec(),
+                r: Response {
+                    samples: Some(vec![
+                        b"mnopqrstuvwxyz123456".to_vec(),
+                        b"mnopqrstuvwxyz654321".to_vec(),
+                    ]),
+                    interval: Some(60),
+                    num: Some(2),
+                    ..Response::new(b"abcdefghij0123456789")
+                },
+                ip: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_get_put() {
+        check(
+            b"d1:ad2:id20:abcdefghij01234567893:seqi3e6:target20:mnopqrstuvwxyz123456e1:q3:get1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::Get {
+                    target: b"mnopqrstuvwxyz123456".to_vec(),
+                    seq: Some(3),
+                },
+            },
+        );
+        check(
+            b"d1:ad2:id20:abcdefghij01234567895:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::Put {
+                    token: b"aoeusnth".to_vec(),
+                    item: Item::Immutable(Value::from("Hello World!")),
+                    cas: None,
+                },
+            },
+        );
+        check(
+            b"d1:ad3:casi1e2:id20:abcdefghij01234567891:k3:key4:salt1:s3:seqi2e3:sig3:sig5:token8:aoeusnth1:v1:xe1:q3:put1:t2:aa1:y1:qe",
+            Krpc::Query {
+                tid: b"aa".to_vec(),
+                id: b"abcdefghij0123456789".to_vec(),
+                ro: false,
+                want: Want::default(),
+                q: QueryKind::Put {
+                    token: b"aoeusnth".to_vec(),
+                    item: Item::Mutable(MutableItem {
+                        k: b"key".to_vec(),
+                        salt: b"s".to_vec(),
+                        seq: 2,
+                        sig: b"sig".to_vec(),
+                        v: Value::from("x"),
+                    }),
+                    cas: Some(1),
+                },
+            },
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let cases: Vec<(&[u8], ParseError)> = vec![
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa",
+                ParseError::Bencode,
+            ),
+            (b"li1ee", ParseError::Invalid("message")),
+            (b"d1:y1:qe", ParseError::Missing("t")),
+            (b"d1:t2:aa1:y1:xe", ParseError::UnknownType("x".to_string())),
+            (
+                b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe",
+                ParseError::Invalid("id"),
+            ),
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe",
+                ParseError::UnknownMethod("vote".to_string()),
+            ),
+            (
+                b"d1:q4:vote1:t2:aa1:y1:qe",
+                ParseError::UnknownMethod("vote".to_string()),
+            ),
+            (b"d1:q4:ping1:t2:aa1:y1:qe", ParseError::Missing("a")),
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe",
+                ParseError::Missing("target"),
+            ),
+            (
+                b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re",
+                ParseError::Invalid("nodes"),
+            ),
+            (b"d1:eli201ee1:t2:aa1:y1:ee", ParseError::Invalid("e")),
+        ];
+
+        for (packet, err) in cases {
+            assert_eq!(Krpc::parse(packet), Err(err));
+        }
+    }
+
+    #[test]
+    fn test_query_tid() {
+        let cases: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe", Some(b"aa")),
+            (b"d1:q4:vote1:t2:bb1:y1:qe", Some(b"bb")),
+            (b"d1:rd2:id3:abce1:t2:aa1:y1:re", None),
+            (b"d1:q4:ping1:y1:qe", None),
+            (b"d1:q4:ping", None),
+        ];
+
+        for (packet, tid) in cases {
+            assert_eq!(query_tid(packet), tid.map(|x| x.to_vec()));
+        }
+    }
+}
@@ -1,5 +1,24 @@
+pub mod bep42;
+pub mod bloom;
+pub mod client;
+pub mod core;
+pub mod coverage;
+pub mod event;
+pub mod identity;
+pub mod item;
+pub mod krpc;
+pub mod lookup;
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+pub mod mmsg;
 pub mod node;
+pub mod routing;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod strategy;
 pub mod torrent;
+pub mod transaction;
+pub mod transport;
 pub mod util;
 
 pub mod message;
@@ -14,8 +33,11 @@ pub use blacklist::BlackList;
 pub mod rate;
 pub use rate::Rate;
 
+pub mod config;
+pub use config::Config;
+
 pub mod dht;
-pub use dht::DHT;
+pub use dht::{AnnounceHandle, RunHandle, Scrape, DHT};
 
 pub mod errors;
 pub use errors::{Error, Result};
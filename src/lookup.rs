@@ -0,0 +1,284 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::node::Node;
+use crate::routing::K;
+use crate::util::distance;
+
+// concurrent queries of a lookup.
+pub const ALPHA: usize = 3;
+
+// a queried node not answering in time is failed.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// cap of candidates kept, the farthest are dropped.
+const CANDIDATES_MAX: usize = K * 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Fresh,
+    Queried(Instant),
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    node: Node,
+    distance: Vec<u8>,
+    status: Status,
+    token: Option<Vec<u8>>,
+    // false until a node added by address answers with its id.
+    known: bool,
+}
+
+// iterative kademlia lookup, closest-first with ALPHA queries in flight.
+// it's done once the K closest live candidates have responded.
+#[derive(Debug)]
+pub struct Lookup {
+    target: Vec<u8>,
+    candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+    pub fn new(target: &[u8]) -> Self {
+        Self {
+            target: target.to_vec(),
+            candidates: Vec::new(),
+        }
+    }
+
+    pub fn target(&self) -> &[u8] {
+        &self.target
+    }
+
+    // add a candidate, known nodes are ignored.
+    pub fn add(&mut self, node: Node) {
+        self.insert(node, true);
+    }
+
+    // add a node of unknown id, e.g. a bootstrap router, it sorts last.
+    pub fn add_addr(&mut self, addr: SocketAddr) {
+        let id = self.target.iter().map(|x| !x).collect();
+        self.insert(Node { id, addr }, false);
+    }
+
+    // nodes of unknown id share a placeholder, they are told apart by addr.
+    fn insert(&mut self, node: Node, known: bool) {
+        if self
+            .candidates
+            .iter()
+            .any(|c| c.node.addr == node.addr || (known && c.known && c.node.id == node.id))
+        {
+            return;
+        }
+
+        let c = Candidate {
+            distance: distance(&node.id, &self.target),
+            node,
+            status: Status::Fresh,
+            token: None,
+            known,
+        };
+        let pos = self
+            .candidates
+            .iter()
+            .position(|x| x.distance > c.distance)
+            .unwrap_or(self.candidates.len());
+        self.candidates.insert(pos, c);
+
+        // only drop candidates never queried, replies must find their entry.
+        if self.candidates.len() > CANDIDATES_MAX {
+            if let Some(pos) = self
+                .candidates
+                .iter()
+                .rposition(|c| c.status == Status::Fresh)
+            {
+                self.candidates.remove(pos);
+            }
+        }
+    }
+
+    // the next nodes to query, at most ALPHA stay in flight.
+    pub fn next(&mut self, now: Instant) -> Vec<Node> {
+        let mut in_flight = self
+            .candidates
+            .iter()
+            .filter(|c| matches!(c.status, Status::Queried(_)))
+            .count();
+
+        let mut res = Vec::new();
+        for c in self.candidates.iter_mut() {
+            if in_flight >= ALPHA {
+                break;
+            }
+
+            if c.status == Status::Fresh {
+                c.status = Status::Queried(now);
+                res.push(c.node.clone());
+                in_flight += 1;
+            }
+        }
+        res
+    }
+
+    // record a response of the node at addr, with the nodes it knows.
+    pub fn on_response(
+        &mut self,
+        addr: &SocketAddr,
+        id: &[u8],
+        token: Option<&[u8]>,
+        nodes: Vec<Node>,
+    ) {
+        if let Some(pos) = self.candidates.iter().position(|c| c.node.addr == *addr) {
+            let mut c = self.candidates.remove(pos);
+            if !matches!(c.status, Status::Queried(_)) {
+                self.candidates.insert(pos, c);
+                return;
+            }
+
+            c.node.id = id.to_vec();
+            c.distance = distance(id, &self.target);
+            c.status = Status::Responded;
+            c.token = token.map(|x| x.to_vec());
+            c.known = true;
+
+            let pos = self
+                .candidates
+                .iter()
+                .position(|x| x.distance > c.distance)
+                .unwrap_or(self.candidates.len());
+            self.candidates.insert(pos, c);
+        }
+
+        for node in nodes {
+            self.add(node);
+        }
+    }
+
+    // fail the node at addr.
+    pub fn on_failure(&mut self, addr: &SocketAddr) {
+        for c in self.candidates.iter_mut() {
+            if c.node.addr == *addr && matches!(c.status, Status::Queried(_)) {
+                c.status = Status::Failed;
+            }
+        }
+    }
+
+    // fail the queries older than QUERY_TIMEOUT.
+    pub fn expire(&mut self, now: Instant) {
+        for c in self.candidates.iter_mut() {
+            if let Status::Queried(t) = c.status {
+                if now.duration_since(t) >= QUERY_TIMEOUT {
+                    c.status = Status::Failed;
+                }
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.candidates
+            .iter()
+            .filter(|c| c.status != Status::Failed)
+            .take(K)
+            .all(|c| c.status == Status::Responded)
+    }
+
+    // the K closest responded nodes with the tokens they handed out.
+    pub fn closest(&self) -> Vec<(Node, Option<Vec<u8>>)> {
+        self.candidates
+            .iter()
+            .filter(|c| c.status == Status::Responded)
+            .take(K)
+            .map(|c| (c.node.clone(), c.token.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8, port: u16) -> Node {
+        let mut id = vec![0; 20];
+        id[0] = first;
+        Node {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn test_closest_first() {
+        let mut lookup = Lookup::new(&[0; 20]);
+        for i in 1..=5u8 {
+            lookup.add(node(i * 10, i.into()));
+        }
+
+        let now = Instant::now();
+        let res = lookup.next(now);
+        assert_eq!(res.len(), ALPHA);
+        assert_eq!(res[0].id[0], 10);
+        assert_eq!(res[2].id[0], 30);
+
+        // nothing more until one answers.
+        assert!(lookup.next(now).is_empty());
+
+        lookup.on_response(&res[0].addr, &res[0].id, Some(b"tk"), vec![node(1, 100)]);
+        let res = lookup.next(now);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id[0], 1);
+    }
+
+    #[test]
+    fn test_done() {
+        let mut lookup = Lookup::new(&[0; 20]);
+        lookup.add(node(1, 1));
+        lookup.add(node(2, 2));
+        assert!(!lookup.is_done());
+
+        let now = Instant::now();
+        let res = lookup.next(now);
+        assert_eq!(res.len(), 2);
+
+        lookup.on_response(&res[0].addr, &res[0].id, Some(b"tk"), Vec::new());
+        assert!(!lookup.is_done());
+
+        lookup.expire(now + QUERY_TIMEOUT);
+        assert!(lookup.is_done());
+
+        let closest = lookup.closest();
+        assert_eq!(closest.len(), 1);
+        assert_eq!(closest[0].0.id[0], 1);
+        assert_eq!(closest[0].1.as_deref(), Some(b"tk".as_ref()));
+    }
+
+    #[test]
+    fn test_unknown_id() {
+        let mut lookup = Lookup::new(&[0; 20]);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        lookup.add_addr(addr);
+        lookup.add(node(0xf0, 1));
+
+        // the router sorts after the known node.
+        let res = lookup.next(Instant::now());
+        assert_eq!(res[1].addr, addr);
+
+        lookup.on_response(&addr, &[0x01; 20], None, Vec::new());
+        assert_eq!(lookup.closest()[0].0.addr, addr);
+    }
+
+    #[test]
+    fn test_bootstrap_addrs() {
+        let mut lookup = Lookup::new(&[0; 20]);
+        let a = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let b = SocketAddr::from(([127, 0, 0, 2], 6881));
+        lookup.add_addr(a);
+        lookup.add_addr(b);
+        lookup.add_addr(a);
+
+        // both routers are queried, once each.
+        let res: Vec<SocketAddr> = lookup.next(Instant::now()).iter().map(|x| x.addr).collect();
+        assert_eq!(res, vec![a, b]);
+    }
+}
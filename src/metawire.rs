@@ -83,7 +83,7 @@ impl MetaWire {
 
     async fn handshake(&self) -> Result<usize> {
         let mut buf = Vec::new();
-        buf.extend_from_slice(&PROTOCOL_HEADER);
+        buf.extend_from_slice(PROTOCOL_HEADER);
         buf.extend_from_slice(&self.message.infohash);
         buf.extend_from_slice(&self.peer_id);
 
@@ -131,7 +131,7 @@ impl MetaWire {
         let mut buf = Vec::new();
         buf.extend_from_slice(&[EXTENDED, EXTHANDSHAKE]);
         buf.extend_from_slice(&data);
-        Ok(self.write(&buf).await?)
+        self.write(&buf).await
     }
 
     async fn next(&self) -> Result<Vec<u8>> {
@@ -206,7 +206,7 @@ impl MetaWire {
         buf.extend_from_slice(&[EXTENDED, ut_metadata as u8]);
         buf.extend_from_slice(&data);
 
-        Ok(self.write(&buf).await?)
+        self.write(&buf).await
     }
 
     async fn on_piece(&self, payload: &[u8]) -> Result<(Vec<u8>, usize)> {
@@ -278,11 +278,9 @@ impl MetaWire {
         let mut res = Vec::new();
         let mut m = sha1::Sha1::new();
 
-        self.pieces.iter().for_each(|x| {
-            x.as_ref().map(|s| {
-                res.extend(s);
-                m.update(&s);
-            });
+        self.pieces.iter().flatten().for_each(|s| {
+            res.extend(s);
+            m.update(s);
         });
 
         (res, m.digest().bytes())
@@ -0,0 +1,192 @@
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+
+use async_io::Async;
+use socket2::SockAddr;
+
+use crate::transport::{BatchFuture, Datagram, IoFuture, Transport};
+
+// udp socket receiving and sending batches of datagrams with one
+// recvmmsg or sendmmsg call (linux).
+#[derive(Debug)]
+pub struct MmsgSocket {
+    sock: Async<UdpSocket>,
+}
+
+impl MmsgSocket {
+    pub fn new(sock: UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            sock: Async::new(sock)?,
+        })
+    }
+}
+
+impl Transport for MmsgSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> IoFuture<'a, usize> {
+        Box::pin(self.sock.send_to(buf, addr))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> IoFuture<'a, (usize, SocketAddr)> {
+        Box::pin(self.sock.recv_from(buf))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.get_ref().local_addr()
+    }
+
+    fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>]) -> IoFuture<'a, Vec<(usize, SocketAddr)>> {
+        Box::pin(async move {
+            self.sock
+                .read_with(|sock| recvmmsg(sock.as_raw_fd(), bufs))
+                .await
+        })
+    }
+
+    fn send_batch<'a>(&'a self, msgs: &'a [Datagram]) -> BatchFuture<'a> {
+        Box::pin(async move {
+            let mut res = Vec::with_capacity(msgs.len());
+            while res.len() < msgs.len() {
+                let sent = res.len();
+                match self
+                    .sock
+                    .write_with(|sock| sendmmsg(sock.as_raw_fd(), &msgs[sent..]))
+                    .await
+                {
+                    Ok(n) if n > 0 => {
+                        res.extend(msgs[sent..sent + n].iter().map(|(buf, _)| Ok(buf.len())))
+                    }
+                    Ok(_) => res.push(Err(io::ErrorKind::WriteZero.into())),
+                    // the first datagram failed, sending goes on past it.
+                    Err(e) => res.push(Err(e)),
+                }
+            }
+            res
+        })
+    }
+}
+
+// receive the datagrams waiting on fd, up to one per buf.
+fn recvmmsg(fd: RawFd, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    // safe, all zero is a valid sockaddr_storage and mmsghdr.
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+    let mut iovs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = addrs
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .map(|(addr, iov)| {
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            hdr.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+
+    // safe, every header points into addrs and iovs, which outlive the call.
+    let n = unsafe {
+        libc::recvmmsg(
+            fd,
+            hdrs.as_mut_ptr(),
+            hdrs.len() as _,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut res = Vec::with_capacity(n as usize);
+    for (hdr, addr) in hdrs.iter().zip(addrs.iter()).take(n as usize) {
+        // safe, the kernel filled addr with msg_namelen bytes.
+        let addr = unsafe { SockAddr::new(*addr, hdr.msg_hdr.msg_namelen) };
+        let addr = addr
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an ip source"))?;
+        res.push((hdr.msg_len as usize, addr));
+    }
+    Ok(res)
+}
+
+// send as many of msgs as the socket takes, returns how many.
+fn sendmmsg(fd: RawFd, msgs: &[Datagram]) -> io::Result<usize> {
+    let addrs: Vec<SockAddr> = msgs.iter().map(|(_, addr)| SockAddr::from(*addr)).collect();
+    let mut iovs: Vec<libc::iovec> = msgs
+        .iter()
+        .map(|(buf, _)| libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = addrs
+        .iter()
+        .zip(iovs.iter_mut())
+        .map(|(addr, iov)| {
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            hdr.msg_hdr.msg_name = addr.as_ptr() as *mut _;
+            hdr.msg_hdr.msg_namelen = addr.len();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+
+    // safe, every header points into addrs and iovs, the kernel only reads them.
+    let n = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, libc::MSG_DONTWAIT) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn test_mmsg() {
+        task::block_on(async {
+            let a = MmsgSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+            let b = MmsgSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+            let to = b.local_addr().unwrap();
+
+            // the ipv6 datagram fails alone on the ipv4 socket.
+            let mut msgs: Vec<Datagram> = (0..5u8).map(|i| (vec![i; 3], to)).collect();
+            msgs.insert(2, (vec![9; 3], "[::1]:6881".parse().unwrap()));
+            let res = a.send_batch(&msgs).await;
+            assert_eq!(res.iter().filter(|x| x.is_ok()).count(), 5);
+            assert!(res[2].is_err());
+            msgs.remove(2);
+
+            let mut bufs = vec![vec![0; 8]; 8];
+            let mut got = Vec::new();
+            while got.len() < 5 {
+                for (i, (n, from)) in b
+                    .recv_batch(&mut bufs)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .enumerate()
+                {
+                    assert_eq!(from, a.local_addr().unwrap());
+                    got.push(bufs[i][..n].to_vec());
+                }
+            }
+            assert_eq!(
+                got,
+                msgs.into_iter().map(|(buf, _)| buf).collect::<Vec<_>>()
+            );
+        });
+    }
+}
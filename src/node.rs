@@ -1,11 +1,12 @@
 use crate::errors::{Error, Result};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 const NODE_BYTES_LENGTH: usize = 26;
+const NODE6_BYTES_LENGTH: usize = 38;
 
 // decode nodes from bytes.
 pub fn decode_nodes(s: &[u8]) -> Result<Vec<Node>> {
-    if s.len() % NODE_BYTES_LENGTH != 0 {
+    if !s.len().is_multiple_of(NODE_BYTES_LENGTH) {
         return Err(Error::Other(format!(
             "invalid replay 'nodes' length={}",
             s.len()
@@ -22,8 +23,79 @@ pub fn decode_nodes(s: &[u8]) -> Result<Vec<Node>> {
     Ok(res)
 }
 
+// decode ipv6 nodes from the bytes of 'nodes6'.
+pub fn decode_nodes6(s: &[u8]) -> Result<Vec<Node>> {
+    if !s.len().is_multiple_of(NODE6_BYTES_LENGTH) {
+        return Err(Error::Other(format!(
+            "invalid replay 'nodes6' length={}",
+            s.len()
+        )));
+    }
+
+    let res = s
+        .chunks(NODE6_BYTES_LENGTH)
+        .map(|x| Node {
+            id: x[..20].to_vec(),
+            // 18 bytes always decode.
+            addr: decode_addr(&x[20..]).unwrap(),
+        })
+        .collect();
+    Ok(res)
+}
+
+// decode a compact ip and port, 6 bytes for ipv4 or 18 bytes for ipv6.
+pub fn decode_addr(s: &[u8]) -> Option<SocketAddr> {
+    let ip = match s.len() {
+        6 => IpAddr::V4(Ipv4Addr::new(s[0], s[1], s[2], s[3])),
+        18 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&s[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes([s[s.len() - 2], s[s.len() - 1]]);
+    Some(SocketAddr::new(ip, port))
+}
+
+// encode an address into compact ip and port.
+pub fn encode_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut res = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    res.extend_from_slice(&addr.port().to_be_bytes());
+    res
+}
+
+// encode nodes into compact bytes, non ipv4 nodes are skipped.
+pub fn encode_nodes<'a, I: IntoIterator<Item = &'a Node>>(nodes: I) -> Vec<u8> {
+    let mut res = Vec::new();
+    for node in nodes {
+        if let SocketAddr::V4(addr) = node.addr {
+            res.extend_from_slice(&node.id);
+            res.extend_from_slice(&addr.ip().octets());
+            res.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    res
+}
+
+// encode nodes into the compact bytes of 'nodes6', non ipv6 nodes are skipped.
+pub fn encode_nodes6<'a, I: IntoIterator<Item = &'a Node>>(nodes: I) -> Vec<u8> {
+    let mut res = Vec::new();
+    for node in nodes {
+        if node.addr.is_ipv6() {
+            res.extend_from_slice(&node.id);
+            res.extend_from_slice(&encode_addr(&node.addr));
+        }
+    }
+    res
+}
+
 // DHT node
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Node {
     pub id: Vec<u8>,
     pub addr: SocketAddr,
@@ -41,3 +113,53 @@ impl Node {
         Self { id, addr }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_nodes() {
+        let nodes = vec![
+            Node {
+                id: vec![1; 20],
+                addr: "127.0.0.1:6881".parse().unwrap(),
+            },
+            Node {
+                id: vec![2; 20],
+                addr: "[::1]:6881".parse().unwrap(),
+            },
+            Node {
+                id: vec![3; 20],
+                addr: "10.0.0.1:80".parse().unwrap(),
+            },
+        ];
+
+        let buf = encode_nodes(&nodes);
+        assert_eq!(buf.len(), 2 * NODE_BYTES_LENGTH);
+
+        let res = decode_nodes(&buf).unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].id, nodes[0].id);
+        assert_eq!(res[0].addr, nodes[0].addr);
+        assert_eq!(res[1].id, nodes[2].id);
+        assert_eq!(res[1].addr, nodes[2].addr);
+        assert!(decode_nodes(&buf[1..]).is_err());
+
+        let buf = encode_nodes6(&nodes);
+        assert_eq!(buf.len(), NODE6_BYTES_LENGTH);
+        assert_eq!(decode_nodes6(&buf).unwrap(), vec![nodes[1].clone()]);
+        assert!(decode_nodes6(&buf[1..]).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode_addr() {
+        ["127.0.0.1:6881", "[2001:db8::1]:443"]
+            .iter()
+            .for_each(|x| {
+                let addr: SocketAddr = x.parse().unwrap();
+                assert_eq!(decode_addr(&encode_addr(&addr)), Some(addr));
+            });
+        assert_eq!(decode_addr(&[0; 5]), None);
+    }
+}
@@ -1,41 +1,243 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
-// 1 second.
-const SECOND: Duration = Duration::from_secs(1);
+use async_std::task;
+use lru_cache::LruCache;
 
+// token bucket refilled with rate tokens per second up to burst tokens.
+// it keeps the time the bucket will be full again, so taking tokens is a
+// single compare and swap.
 #[derive(Debug)]
 pub struct Rate {
-    limit: AtomicUsize,
-    num: AtomicUsize,
-    last: Mutex<Instant>,
+    // nanoseconds to refill one token, zero means unlimited.
+    interval: u64,
+    // nanoseconds to refill the whole bucket.
+    capacity: u64,
+    // when the bucket is full again, in nanoseconds since start.
+    full: AtomicU64,
+    start: Instant,
 }
 
 impl Rate {
-    pub fn new(n: usize) -> Self {
+    // a full bucket, rate 0 never limits.
+    pub fn new(rate: usize, burst: usize) -> Self {
+        let (interval, capacity) = bucket(rate, burst);
         Self {
-            limit: AtomicUsize::new(n),
-            num: AtomicUsize::new(0),
-            last: Mutex::new(Instant::now()),
+            interval,
+            capacity,
+            full: AtomicU64::new(0),
+            start: Instant::now(),
         }
     }
 
-    pub fn allow(&self) -> bool {
-        let mut last = self.last.lock().unwrap();
+    // take n tokens if the bucket holds them.
+    pub fn try_acquire(&self, n: usize) -> bool {
+        self.try_acquire_at(n, Instant::now())
+    }
+
+    // take n tokens if the bucket holds them at now.
+    pub fn try_acquire_at(&self, n: usize, now: Instant) -> bool {
+        let now = self.nanos(now);
+        let mut full = self.full.load(Ordering::Relaxed);
+        loop {
+            let next = match take(full, now, self.interval * n as u64, self.capacity) {
+                Some(x) => x,
+                None => return false,
+            };
+
+            match self
+                .full
+                .compare_exchange_weak(full, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(x) => full = x,
+            }
+        }
+    }
+
+    // take n tokens, waiting for the bucket to refill them. callers are
+    // served in order, so a busy bucket spaces them out evenly.
+    pub async fn acquire(&self, n: usize) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            task::sleep(wait).await;
+        }
+    }
+
+    // take n tokens ahead, returns how long until they are refilled at now.
+    fn reserve(&self, n: usize, now: Instant) -> Duration {
+        let now = self.nanos(now);
+        let mut full = self.full.load(Ordering::Relaxed);
+        loop {
+            let next = full.max(now) + self.interval * n as u64;
+            match self
+                .full
+                .compare_exchange_weak(full, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Duration::from_nanos(next.saturating_sub(now + self.capacity)),
+                Err(x) => full = x,
+            }
+        }
+    }
+
+    fn nanos(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_nanos() as u64
+    }
+}
+
+// nanoseconds to refill one token and the whole bucket, zero means unlimited.
+fn bucket(rate: usize, burst: usize) -> (u64, u64) {
+    let interval = match rate {
+        0 => 0,
+        n => 1_000_000_000 / n as u64,
+    };
+    (interval, interval * burst.max(1) as u64)
+}
+
+// when the bucket full at full is full again after taking cost at now,
+// none if it doesn't hold cost.
+fn take(full: u64, now: u64, cost: u64, capacity: u64) -> Option<u64> {
+    let next = full.max(now) + cost;
+    if next - now > capacity {
+        return None;
+    }
+    Some(next)
+}
+
+// per source limiter, a token bucket for every ip and one for every /24
+// ipv4 or /48 ipv6 network. the least recently seen sources are forgotten,
+// they come back with a full bucket.
+#[derive(Debug)]
+pub struct KeyedRate {
+    ip: (u64, u64),
+    net: (u64, u64),
+    ips: Mutex<LruCache<IpAddr, u64>>,
+    nets: Mutex<LruCache<IpAddr, u64>>,
+    start: Instant,
+}
+
+impl KeyedRate {
+    // ip_rate per second for every ip and net_rate for every network, each
+    // with a second of burst, starting at now. capacity bounds the sources
+    // remembered.
+    pub fn new(ip_rate: usize, net_rate: usize, capacity: usize, now: Instant) -> Self {
+        Self {
+            ip: bucket(ip_rate, ip_rate),
+            net: bucket(net_rate, net_rate),
+            ips: Mutex::new(LruCache::new(capacity)),
+            nets: Mutex::new(LruCache::new(capacity)),
+            start: now,
+        }
+    }
+
+    // take a token of ip and its network at now, both must hold one.
+    pub fn try_acquire_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let now = now.saturating_duration_since(self.start).as_nanos() as u64;
+        let net = network(ip);
 
-        if self.num.load(Ordering::Relaxed) >= self.limit.load(Ordering::Relaxed) {
-            let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+        let mut nets = self.nets.lock().unwrap();
+        let full_ip = ips.get_mut(&ip).map_or(0, |x| *x);
+        let full_net = nets.get_mut(&net).map_or(0, |x| *x);
 
-            if now.duration_since(*last) < SECOND {
-                return false;
+        match (
+            take(full_ip, now, self.ip.0, self.ip.1),
+            take(full_net, now, self.net.0, self.net.1),
+        ) {
+            (Some(x), Some(y)) => {
+                ips.insert(ip, x);
+                nets.insert(net, y);
+                true
             }
+            _ => false,
+        }
+    }
+}
 
-            self.num.store(0, Ordering::Relaxed);
-            *last = now;
+// the /24 of an ipv4 or the /48 of an ipv6 address.
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
         }
+        IpAddr::V6(ip) => {
+            let mut octets = [0; 16];
+            octets[..6].copy_from_slice(&ip.octets()[..6]);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_acquire() {
+        let rate = Rate::new(10, 5);
+        let now = Instant::now();
+
+        // the burst goes at once, then one token per 100ms.
+        assert!(rate.try_acquire_at(3, now));
+        assert!(rate.try_acquire_at(2, now));
+        assert!(!rate.try_acquire_at(1, now));
 
-        self.num.fetch_add(1, Ordering::Relaxed);
-        true
+        let later = now + Duration::from_millis(100);
+        assert!(rate.try_acquire_at(1, later));
+        assert!(!rate.try_acquire_at(1, later));
+
+        // more than the burst never fits.
+        let idle = now + Duration::from_secs(10);
+        assert!(!rate.try_acquire_at(6, idle));
+        assert!(rate.try_acquire_at(5, idle));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let rate = Rate::new(10, 2);
+        let now = Instant::now();
+
+        assert_eq!(rate.reserve(2, now), Duration::ZERO);
+        assert_eq!(rate.reserve(1, now), Duration::from_millis(100));
+        assert_eq!(rate.reserve(1, now), Duration::from_millis(200));
+        assert!(!rate.try_acquire_at(1, now + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn test_keyed() {
+        let now = Instant::now();
+        let rate = KeyedRate::new(2, 3, 16, now);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // two per ip, three per /24.
+        assert!(rate.try_acquire_at(ip("10.0.0.1"), now));
+        assert!(rate.try_acquire_at(ip("10.0.0.1"), now));
+        assert!(!rate.try_acquire_at(ip("10.0.0.1"), now));
+        assert!(rate.try_acquire_at(ip("10.0.0.2"), now));
+        assert!(!rate.try_acquire_at(ip("10.0.0.3"), now));
+        assert!(rate.try_acquire_at(ip("10.0.1.1"), now));
+
+        // /48 for ipv6.
+        assert!(rate.try_acquire_at(ip("2001:db8:1::1"), now));
+        assert!(rate.try_acquire_at(ip("2001:db8:1::2"), now));
+        assert!(rate.try_acquire_at(ip("2001:db8:1:ff::3"), now));
+        assert!(!rate.try_acquire_at(ip("2001:db8:1:ff::4"), now));
+        assert!(rate.try_acquire_at(ip("2001:db8:2::1"), now));
+
+        let later = now + Duration::from_secs(1);
+        assert!(rate.try_acquire_at(ip("10.0.0.3"), later));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let rate = Rate::new(0, 0);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(rate.try_acquire_at(100, now));
+        }
+        task::block_on(rate.acquire(100));
     }
 }
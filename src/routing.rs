@@ -0,0 +1,458 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bep42;
+use crate::util::{common_prefix_len, distance, rand_id_in_bucket};
+
+// max nodes per bucket.
+pub const K: usize = 8;
+
+// one bucket for every possible common prefix length.
+const BUCKET_COUNT: usize = 160;
+
+// nodes quiet for longer are questionable, buckets unchanged for longer are refreshed (BEP 5).
+pub const IDLE: Duration = Duration::from_secs(15 * 60);
+
+// nodes failing to answer this many queries in a row are bad.
+const FAILURES_MAX: u32 = 2;
+
+// a questionable node is pinged once per this interval.
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+// liveness of a node (BEP 5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Good,
+    Questionable,
+    Bad,
+}
+
+// routing table entry.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: Vec<u8>,
+    pub addr: SocketAddr,
+    // last response to one of our queries.
+    pub last_seen: SystemTime,
+    // last query from the node.
+    pub last_query: Option<SystemTime>,
+    // queries in a row the node did not answer.
+    pub failures: u32,
+    // last ping checking whether a questionable node is alive.
+    pub pinged: Option<SystemTime>,
+    // id matches the address, always true unless BEP 42 is enforced.
+    pub secure: bool,
+}
+
+impl Entry {
+    // good nodes answered or queried us within IDLE, bad nodes stopped answering.
+    pub fn status(&self, now: SystemTime) -> Status {
+        let recent = |t: SystemTime| now.duration_since(t).map_or(true, |d| d < IDLE);
+
+        if self.failures >= FAILURES_MAX {
+            Status::Bad
+        } else if recent(self.last_seen) || self.last_query.is_some_and(recent) {
+            Status::Good
+        } else {
+            Status::Questionable
+        }
+    }
+}
+
+// what an insert did to the routing table.
+#[derive(Debug, Clone)]
+pub enum Inserted {
+    // the node is new, it took the place of replaced when its bucket was full.
+    New { replaced: Option<Entry> },
+    // the node was known.
+    Refreshed,
+    // the node was not stored.
+    Dropped,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    entries: Vec<Entry>,
+    // last time a node was added, answered or the bucket was refreshed.
+    changed: SystemTime,
+}
+
+// kademlia routing table, buckets indexed by the common prefix length
+// between the node id and the local id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    local_id: Vec<u8>,
+    buckets: Vec<Bucket>,
+    enforce_bep42: bool,
+}
+
+impl RoutingTable {
+    // with enforce_bep42, nodes whose id doesn't match their address are
+    // replaced first when a bucket is full and are returned last.
+    pub fn new(local_id: &[u8], enforce_bep42: bool) -> Self {
+        let bucket = Bucket {
+            entries: Vec::new(),
+            changed: UNIX_EPOCH,
+        };
+        Self {
+            local_id: local_id.to_vec(),
+            buckets: vec![bucket; BUCKET_COUNT],
+            enforce_bep42,
+        }
+    }
+
+    pub fn local_id(&self) -> &[u8] {
+        &self.local_id
+    }
+
+    // move to a new local id, nodes are put into the buckets of the new id.
+    pub fn set_local_id(&mut self, local_id: &[u8]) {
+        let entries: Vec<Entry> = self
+            .buckets
+            .iter_mut()
+            .flat_map(|b| b.entries.drain(..))
+            .collect();
+        self.local_id = local_id.to_vec();
+
+        for e in entries {
+            if e.id == self.local_id {
+                continue;
+            }
+            let index = self.bucket_index(&e.id);
+            let bucket = &mut self.buckets[index];
+            if bucket.entries.len() < K {
+                bucket.changed = bucket.changed.max(e.last_seen);
+                bucket.entries.push(e);
+            }
+        }
+    }
+
+    // insert or refresh a node which answered at last_seen, returns false
+    // when the node was dropped. bad nodes are replaced first.
+    pub fn insert(&mut self, id: &[u8], addr: SocketAddr, last_seen: SystemTime) -> bool {
+        !matches!(self.add(id, addr, last_seen), Inserted::Dropped)
+    }
+
+    // insert, telling whether the node is new and which node it replaced.
+    pub fn add(&mut self, id: &[u8], addr: SocketAddr, last_seen: SystemTime) -> Inserted {
+        if id.len() != self.local_id.len() || id == self.local_id.as_slice() {
+            return Inserted::Dropped;
+        }
+
+        let index = self.bucket_index(id);
+        let bucket = &mut self.buckets[index];
+        if let Some(pos) = bucket.entries.iter().position(|e| e.id == id) {
+            let mut entry = bucket.entries.remove(pos);
+            entry.addr = addr;
+            entry.last_seen = entry.last_seen.max(last_seen);
+            entry.failures = 0;
+            entry.pinged = None;
+            bucket.entries.push(entry);
+            bucket.changed = bucket.changed.max(last_seen);
+            return Inserted::Refreshed;
+        }
+
+        let secure = !self.enforce_bep42 || bep42::is_valid_id(id, &addr.ip());
+        let mut replaced = None;
+        if bucket.entries.len() >= K {
+            let bad = bucket
+                .entries
+                .iter()
+                .position(|e| e.failures >= FAILURES_MAX);
+            let insecure = bucket.entries.iter().position(|e| !e.secure);
+            match bad.or(insecure.filter(|_| secure)) {
+                Some(pos) => replaced = Some(bucket.entries.remove(pos)),
+                None => return Inserted::Dropped,
+            }
+        }
+
+        bucket.entries.push(Entry {
+            id: id.to_vec(),
+            addr,
+            last_seen,
+            last_query: None,
+            failures: 0,
+            pinged: None,
+            secure,
+        });
+        bucket.changed = bucket.changed.max(last_seen);
+        Inserted::New { replaced }
+    }
+
+    // a query from a known node at now keeps it good.
+    pub fn on_query(&mut self, id: &[u8], addr: &SocketAddr, now: SystemTime) {
+        if let Some(e) = self.get_mut(id).filter(|e| e.addr == *addr) {
+            e.last_query = Some(now);
+        }
+    }
+
+    // a query to addr timed out.
+    pub fn on_failure(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(e) = bucket.entries.iter_mut().find(|e| e.addr == *addr) {
+                e.failures += 1;
+                return;
+            }
+        }
+    }
+
+    // when the bucket of id is full, the least recently seen questionable node
+    // to ping. once it fails enough pings, it is replaced by the next insert.
+    pub fn questionable(&mut self, id: &[u8], now: SystemTime) -> Option<SocketAddr> {
+        let index = self.bucket_index(id);
+        let bucket = &mut self.buckets[index];
+        if bucket.entries.len() < K {
+            return None;
+        }
+
+        let e = bucket
+            .entries
+            .iter_mut()
+            .filter(|e| e.status(now) == Status::Questionable)
+            .filter(|e| {
+                e.pinged
+                    .is_none_or(|t| now.duration_since(t).is_ok_and(|d| d >= PING_INTERVAL))
+            })
+            .min_by_key(|e| e.last_seen)?;
+        e.pinged = Some(now);
+        Some(e.addr)
+    }
+
+    // random targets in the buckets unchanged for IDLE, up to the deepest
+    // bucket holding nodes. the buckets count as refreshed at now.
+    pub fn refresh(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        let deepest = match self.buckets.iter().rposition(|b| !b.entries.is_empty()) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        let mut targets = Vec::new();
+        for (i, bucket) in self.buckets[..=deepest].iter_mut().enumerate() {
+            if now.duration_since(bucket.changed).is_ok_and(|d| d >= IDLE) {
+                bucket.changed = now;
+                targets.push(rand_id_in_bucket(&self.local_id, i));
+            }
+        }
+        targets
+    }
+
+    // the n known nodes closest to target, secure nodes first. bad nodes are skipped.
+    pub fn closest(&self, target: &[u8], n: usize) -> Vec<Entry> {
+        self.closest_where(target, n, |_| true)
+    }
+
+    // like closest, among the nodes whose address passes f.
+    pub fn closest_where<F>(&self, target: &[u8], n: usize, f: F) -> Vec<Entry>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let mut res: Vec<Entry> = self
+            .entries()
+            .filter(|e| e.failures < FAILURES_MAX && f(&e.addr))
+            .cloned()
+            .collect();
+        res.sort_by_key(|e| (!e.secure, distance(&e.id, target)));
+        res.truncate(n);
+        res
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.buckets.iter().flat_map(|b| b.entries.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.entries.is_empty())
+    }
+
+    fn get_mut(&mut self, id: &[u8]) -> Option<&mut Entry> {
+        let index = self.bucket_index(id);
+        self.buckets[index].entries.iter_mut().find(|e| e.id == id)
+    }
+
+    fn bucket_index(&self, id: &[u8]) -> usize {
+        common_prefix_len(id, &self.local_id).min(BUCKET_COUNT - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rand_infohash_key;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_insert() {
+        let local = vec![0; 20];
+        let mut table = RoutingTable::new(&local, false);
+
+        // local id is never stored.
+        assert!(!table.insert(&local, addr(1), SystemTime::now()));
+        assert!(table.is_empty());
+
+        let id = rand_infohash_key();
+        assert!(table.insert(&id, addr(1), SystemTime::now()));
+        assert!(table.insert(&id, addr(2), SystemTime::now()));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.entries().next().unwrap().addr, addr(2));
+    }
+
+    #[test]
+    fn test_bucket_full() {
+        let local = vec![0; 20];
+        let mut table = RoutingTable::new(&local, false);
+
+        // all ids share no prefix bit with the local id, same bucket.
+        for i in 0..K + 2 {
+            let mut id = vec![0xff; 20];
+            id[19] = i as u8;
+            let ok = table.insert(&id, addr(i as u16), SystemTime::now());
+            assert_eq!(ok, i < K);
+        }
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_closest() {
+        let local = vec![0; 20];
+        let mut table = RoutingTable::new(&local, false);
+
+        for i in 1..=4u8 {
+            let mut id = vec![0; 20];
+            id[0] = i;
+            table.insert(&id, addr(i.into()), SystemTime::now());
+        }
+
+        let mut target = vec![0; 20];
+        target[0] = 3;
+        let res = table.closest(&target, 2);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].id[0], 3);
+        assert_eq!(res[1].id[0], 2);
+    }
+
+    #[test]
+    fn test_enforce_bep42() {
+        let ip = "124.31.75.21".parse().unwrap();
+        let addr = SocketAddr::new(ip, 6881);
+        let id = bep42::make_id(&ip, 0);
+
+        // all ids below share no prefix bit with the local id, same bucket.
+        let mut local = vec![0; 20];
+        local[0] = id[0] ^ 0x80;
+        let mut table = RoutingTable::new(&local, true);
+
+        // fill the bucket with nodes failing the check.
+        for i in 0..K {
+            let mut other = vec![0; 20];
+            other[0] = id[0];
+            other[19] = 0x10 + i as u8;
+            assert!(table.insert(&other, addr, SystemTime::now()));
+        }
+        assert!(table.entries().all(|e| !e.secure));
+
+        // a secure node replaces one of them and is returned first.
+        assert!(table.insert(&id, addr, SystemTime::now()));
+        assert_eq!(table.len(), K);
+        assert_eq!(table.closest(&local, 1)[0].id, id);
+    }
+
+    #[test]
+    fn test_set_local_id() {
+        let mut table = RoutingTable::new(&[0; 20], false);
+        for i in 1..=4u8 {
+            table.insert(&[i; 20], addr(i.into()), SystemTime::now());
+        }
+
+        table.set_local_id(&[1; 20]);
+        assert_eq!(table.local_id(), &[1; 20]);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn test_status() {
+        let now = SystemTime::now();
+        let mut table = RoutingTable::new(&[0; 20], false);
+        table.insert(&[1; 20], addr(1), now - IDLE);
+
+        let status = |t: &RoutingTable| t.entries().next().unwrap().status(now);
+        assert_eq!(status(&table), Status::Questionable);
+
+        // a query from the node keeps it good.
+        table.on_query(&[1; 20], &addr(1), now);
+        assert_eq!(status(&table), Status::Good);
+
+        for _ in 0..FAILURES_MAX {
+            table.on_failure(&addr(1));
+        }
+        assert_eq!(status(&table), Status::Bad);
+        assert!(table.closest(&[1; 20], K).is_empty());
+
+        // an answer clears the failures.
+        table.insert(&[1; 20], addr(1), now);
+        assert_eq!(status(&table), Status::Good);
+    }
+
+    #[test]
+    fn test_evict_questionable() {
+        let now = SystemTime::now();
+        let mut table = RoutingTable::new(&[0; 20], false);
+
+        // a full bucket, the first node has been quiet for long.
+        for i in 0..K {
+            let mut id = vec![0xff; 20];
+            id[19] = i as u8;
+            let last_seen = if i == 0 { now - IDLE } else { now };
+            assert!(table.insert(&id, addr(i as u16), last_seen));
+        }
+
+        let new = vec![0xfe; 20];
+        assert!(!table.insert(&new, addr(100), now));
+
+        // the questionable node is pinged, once per interval.
+        assert_eq!(table.questionable(&new, now), Some(addr(0)));
+        assert_eq!(table.questionable(&new, now), None);
+
+        // it failed to answer, its place goes to the new node.
+        for _ in 0..FAILURES_MAX {
+            table.on_failure(&addr(0));
+        }
+        match table.add(&new, addr(100), now) {
+            Inserted::New { replaced: Some(e) } => assert_eq!(e.addr, addr(0)),
+            x => panic!("unexpected {:?}", x),
+        }
+        assert!(matches!(
+            table.add(&new, addr(100), now),
+            Inserted::Refreshed
+        ));
+        assert_eq!(table.len(), K);
+        assert!(table.entries().all(|e| e.addr != addr(0)));
+    }
+
+    #[test]
+    fn test_refresh() {
+        let now = SystemTime::now();
+        let mut table = RoutingTable::new(&[0; 20], false);
+        assert!(table.refresh(now).is_empty());
+
+        // buckets 0 to 2 hold nodes, only the quiet one is refreshed.
+        table.insert(&[0xff; 20], addr(1), now);
+        table.insert(&[0x7f; 20], addr(2), now - IDLE);
+        table.insert(&[0x3f; 20], addr(3), now);
+
+        let targets = table.refresh(now);
+        let mut buckets: Vec<_> = targets.iter().map(|t| table.bucket_index(t)).collect();
+        buckets.sort_unstable();
+        assert_eq!(buckets, vec![1]);
+
+        // refreshed buckets wait another IDLE.
+        assert!(table.refresh(now).is_empty());
+        assert_eq!(table.refresh(now + IDLE).len(), 3);
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::routing::Entry;
+use crate::util::{from_hex, to_hex};
+use crate::{Error, Result};
+
+// persisted routing table node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeState {
+    pub id: String,
+    pub addr: SocketAddr,
+    // unix timestamp in seconds.
+    pub last_seen: u64,
+}
+
+impl NodeState {
+    pub fn from_entry(e: &Entry) -> Self {
+        let last_seen = e
+            .last_seen
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            id: to_hex(&e.id),
+            addr: e.addr,
+            last_seen,
+        }
+    }
+
+    pub fn id(&self) -> Option<Vec<u8>> {
+        from_hex(&self.id)
+    }
+
+    pub fn last_seen(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_seen)
+    }
+}
+
+// persisted virtual node, its id and routing table.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityState {
+    pub id: String,
+    pub nodes: Vec<NodeState>,
+}
+
+impl IdentityState {
+    pub fn new(id: &[u8], entries: &[Entry]) -> Self {
+        Self {
+            id: to_hex(id),
+            nodes: entries.iter().map(NodeState::from_entry).collect(),
+        }
+    }
+
+    pub fn id(&self) -> Option<Vec<u8>> {
+        from_hex(&self.id)
+    }
+}
+
+// DHT state kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct State {
+    pub identities: Vec<IdentityState>,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    // write to a temporary file first, so a crash never leaves a truncated state.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path).map_err(Error::from)
+    }
+}
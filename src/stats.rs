@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+// error replies sent, by class.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    // 201, the query failed for another reason.
+    pub generic: u64,
+    // 203, malformed queries, missing arguments and invalid tokens.
+    pub protocol: u64,
+    // 204.
+    pub method_unknown: u64,
+    // items refused by put (BEP 44).
+    pub storage: u64,
+}
+
+impl ErrorCounts {
+    pub fn total(&self) -> u64 {
+        self.generic + self.protocol + self.method_unknown + self.storage
+    }
+}
+
+// messages and valid announces received from the nodes of one client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientCounts {
+    pub messages: u64,
+    pub announces: u64,
+}
+
+// counters of a running DHT since it started.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // queries received, by method.
+    pub queries: BTreeMap<&'static str, u64>,
+    // responses received to our queries.
+    pub replies: u64,
+    // error replies received.
+    pub errors_received: u64,
+    pub errors_sent: ErrorCounts,
+    // queries ignored because their ip or network went over its rate limit.
+    pub queries_dropped: u64,
+    // datagrams the socket refused to send.
+    pub sends_failed: u64,
+    // datagrams which are not valid krpc messages.
+    pub decode_failures: u64,
+    // announce_peer and put with a token we did not hand out.
+    pub invalid_tokens: u64,
+    // announces passed to the receiver of DHT::run.
+    pub announces: u64,
+    // announces lost because the receiver was full.
+    pub announces_dropped: u64,
+    // events lost because a subscriber was full.
+    pub events_dropped: u64,
+    // find_node of the crawl dropped because the rate limit queue was full.
+    pub find_node_suppressed: u64,
+    // nodes in the routing tables of all identities.
+    pub nodes: usize,
+    // by client name from the 'v' of the messages, "unknown" without one.
+    pub clients: BTreeMap<String, ClientCounts>,
+    // leading bytes of the keyspace we heard at least one node from, of 256.
+    pub prefixes: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let queries: u64 = self.queries.values().sum();
+        write!(
+            f,
+            "packets in {} ({} bytes) out {} ({} bytes), send failures {}, queries {}",
+            self.packets_in,
+            self.bytes_in,
+            self.packets_out,
+            self.bytes_out,
+            self.sends_failed,
+            queries
+        )?;
+        if !self.queries.is_empty() {
+            let methods: Vec<_> = self
+                .queries
+                .iter()
+                .map(|(method, n)| format!("{} {}", method, n))
+                .collect();
+            write!(f, " ({})", methods.join(", "))?;
+        }
+        write!(
+            f,
+            ", rate limited {}, replies {}, errors in {} out {}, decode failures {}, \
+             invalid tokens {}, announces {} dropped {}, events dropped {}, \
+             find_node suppressed {}, nodes {}, prefixes {}/256",
+            self.queries_dropped,
+            self.replies,
+            self.errors_received,
+            self.errors_sent.total(),
+            self.decode_failures,
+            self.invalid_tokens,
+            self.announces,
+            self.announces_dropped,
+            self.events_dropped,
+            self.find_node_suppressed,
+            self.nodes,
+            self.prefixes
+        )?;
+        if !self.clients.is_empty() {
+            // messages and announces of every client.
+            let clients: Vec<_> = self
+                .clients
+                .iter()
+                .map(|(name, n)| format!("{} {}/{}", name, n.messages, n.announces))
+                .collect();
+            write!(f, ", clients ({})", clients.join(", "))?;
+        }
+        Ok(())
+    }
+}
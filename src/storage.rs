@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bencode::Value;
+use lru_cache::LruCache;
+
+use crate::bloom::BloomFilter;
+use crate::item::{check_value, immutable_target, Item, ItemError, MutableItem};
+
+// items not put again within this are dropped.
+const ITEM_EXPIRE: Duration = Duration::from_secs(2 * 60 * 60);
+
+// peers not announced again within this are dropped.
+const PEER_EXPIRE: Duration = Duration::from_secs(30 * 60);
+
+// max peers kept per infohash.
+const PEERS_PER_TORRENT: usize = 256;
+
+// an item and when it was last put.
+#[derive(Debug)]
+struct Stored {
+    item: Item,
+    put: Instant,
+}
+
+// bounded store of BEP 44 items keyed by target, least recently used are dropped.
+#[derive(Clone, Debug)]
+pub struct ItemStore {
+    cache: Arc<Mutex<LruCache<Vec<u8>, Stored>>>,
+}
+
+impl ItemStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    // the item of target at now.
+    pub fn get(&self, target: &[u8], now: Instant) -> Option<Item> {
+        let mut cache = self.cache.lock().unwrap();
+
+        let expired = match cache.get_mut(target) {
+            Some(x) => now.saturating_duration_since(x.put) > ITEM_EXPIRE,
+            None => return None,
+        };
+
+        if expired {
+            cache.remove(target);
+            return None;
+        }
+        cache.get_mut(target).map(|x| x.item.clone())
+    }
+
+    // store an immutable item at now, returns its target.
+    pub fn put_immutable(&self, v: Value, now: Instant) -> Result<Vec<u8>, ItemError> {
+        check_value(&v)?;
+        let target = immutable_target(&v).map_err(|_| ItemError::TooBig)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        let item = Item::Immutable(v);
+        cache.insert(target.clone(), Stored { item, put: now });
+        Ok(target)
+    }
+
+    // store a mutable item at now, cas is the sequence number the writer
+    // expects to replace.
+    pub fn put_mutable(
+        &self,
+        item: MutableItem,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<Vec<u8>, ItemError> {
+        item.verify()?;
+        let target = item.target();
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(Stored {
+            item: Item::Mutable(cur),
+            ..
+        }) = cache.get_mut(&target)
+        {
+            if cas.is_some_and(|x| x != cur.seq) {
+                return Err(ItemError::CasMismatch);
+            }
+
+            if item.seq < cur.seq || (item.seq == cur.seq && item.v != cur.v) {
+                return Err(ItemError::SeqTooLow);
+            }
+        }
+
+        let item = Item::Mutable(item);
+        cache.insert(target.clone(), Stored { item, put: now });
+        Ok(target)
+    }
+}
+
+// an announced peer, and when it was last announced.
+#[derive(Debug)]
+struct Peer {
+    seed: bool,
+    announced: Instant,
+}
+
+// the peers announced for one infohash.
+type Swarm = LruCache<SocketAddr, Peer>;
+
+// bounded store of announced peers keyed by infohash, for BEP 33 scrapes.
+#[derive(Clone, Debug)]
+pub struct PeerStore {
+    cache: Arc<Mutex<LruCache<Vec<u8>, Swarm>>>,
+}
+
+impl PeerStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    // peer announced infohash at now.
+    pub fn insert(&self, infohash: &[u8], peer: SocketAddr, seed: bool, now: Instant) {
+        let mut cache = self.cache.lock().unwrap();
+        if !cache.contains_key(infohash) {
+            cache.insert(infohash.to_vec(), LruCache::new(PEERS_PER_TORRENT));
+        }
+
+        if let Some(peers) = cache.get_mut(infohash) {
+            peers.insert(
+                peer,
+                Peer {
+                    seed,
+                    announced: now,
+                },
+            );
+        }
+    }
+
+    // bloom filters of the seeds and of the other peers of infohash at now,
+    // none if no peer announced it.
+    pub fn scrape(&self, infohash: &[u8], now: Instant) -> Option<(BloomFilter, BloomFilter)> {
+        let mut cache = self.cache.lock().unwrap();
+        let peers = cache.get_mut(infohash)?;
+
+        let mut seeds = BloomFilter::new();
+        let mut leechers = BloomFilter::new();
+        for (addr, peer) in peers.iter() {
+            if now.saturating_duration_since(peer.announced) > PEER_EXPIRE {
+                continue;
+            }
+
+            match peer.seed {
+                true => seeds.insert(&addr.ip()),
+                false => leechers.insert(&addr.ip()),
+            }
+        }
+        Some((seeds, leechers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::Keypair;
+
+    #[test]
+    fn test_immutable() {
+        let now = Instant::now();
+        let store = ItemStore::new(2);
+        let target = store
+            .put_immutable(Value::from("Hello World!"), now)
+            .unwrap();
+        assert_eq!(
+            store.get(&target, now),
+            Some(Item::Immutable(Value::from("Hello World!")))
+        );
+        assert_eq!(store.get(&target, now + ITEM_EXPIRE * 2), None);
+
+        // the least recently used item goes first.
+        let target = store.put_immutable(Value::from(0), now).unwrap();
+        store.put_immutable(Value::from(1), now).unwrap();
+        store.put_immutable(Value::from(2), now).unwrap();
+        assert_eq!(store.get(&target, now), None);
+    }
+
+    #[test]
+    fn test_mutable() {
+        let now = Instant::now();
+        let store = ItemStore::new(8);
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let item = |seq, v| MutableItem::new(&keypair, b"", seq, Value::from(v)).unwrap();
+
+        let target = store.put_mutable(item(2, "a"), None, now).unwrap();
+        assert_eq!(store.get(&target, now), Some(Item::Mutable(item(2, "a"))));
+
+        // same seq and value is a refresh.
+        assert_eq!(
+            store.put_mutable(item(2, "a"), None, now),
+            Ok(target.clone())
+        );
+        assert_eq!(
+            store.put_mutable(item(2, "b"), None, now),
+            Err(ItemError::SeqTooLow)
+        );
+        assert_eq!(
+            store.put_mutable(item(1, "b"), None, now),
+            Err(ItemError::SeqTooLow)
+        );
+        assert_eq!(
+            store.put_mutable(item(3, "b"), Some(1), now),
+            Err(ItemError::CasMismatch)
+        );
+        assert_eq!(
+            store.put_mutable(item(3, "b"), Some(2), now),
+            Ok(target.clone())
+        );
+
+        let mut bad = item(4, "c");
+        bad.seq = 5;
+        assert_eq!(
+            store.put_mutable(bad, None, now),
+            Err(ItemError::InvalidSignature)
+        );
+        assert_eq!(store.get(&target, now), Some(Item::Mutable(item(3, "b"))));
+    }
+
+    #[test]
+    fn test_peers() {
+        let now = Instant::now();
+        let store = PeerStore::new(8);
+        let infohash = [1; 20];
+        assert!(store.scrape(&infohash, now).is_none());
+
+        store.insert(&infohash, "10.0.0.1:6881".parse().unwrap(), true, now);
+        store.insert(&infohash, "10.0.0.2:6881".parse().unwrap(), false, now);
+        let later = now + PEER_EXPIRE;
+        store.insert(&infohash, "10.0.0.3:6881".parse().unwrap(), false, later);
+
+        let (seeds, leechers) = store.scrape(&infohash, now).unwrap();
+        assert_eq!(seeds.estimate().round(), 1.0);
+        assert_eq!(leechers.estimate().round(), 2.0);
+
+        // the first two announces expire.
+        let (seeds, leechers) = store.scrape(&infohash, later + PEER_EXPIRE / 2).unwrap();
+        assert_eq!(seeds.estimate().round(), 0.0);
+        assert_eq!(leechers.estimate().round(), 1.0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::util::{neighbor_id, rand_infohash_key};
+use crate::{Error, Result};
+
+// default bytes of the target a neighbor id copies.
+pub const CLOSENESS: usize = 15;
+
+// the node id we present to a remote, in the find_node queries of the crawl
+// and the get_peers responses. target is the remote's id and local the real
+// id of the identity talking to it.
+pub trait NodeIdStrategy: Debug + Send + Sync {
+    fn node_id(&self, target: &[u8], local: &[u8]) -> Vec<u8>;
+}
+
+// always the real id, the only one valid under BEP 42.
+#[derive(Debug)]
+pub struct LocalId;
+
+impl NodeIdStrategy for LocalId {
+    fn node_id(&self, _target: &[u8], local: &[u8]) -> Vec<u8> {
+        local.to_vec()
+    }
+}
+
+// the first closeness bytes of the target and the rest of the real id, so the
+// remote files us in its closest bucket.
+#[derive(Debug)]
+pub struct Neighbor {
+    pub closeness: usize,
+}
+
+impl NodeIdStrategy for Neighbor {
+    fn node_id(&self, target: &[u8], local: &[u8]) -> Vec<u8> {
+        neighbor_id(target, local, self.closeness)
+    }
+}
+
+// a fresh random id on every message.
+#[derive(Debug)]
+pub struct RandomId;
+
+impl NodeIdStrategy for RandomId {
+    fn node_id(&self, _target: &[u8], _local: &[u8]) -> Vec<u8> {
+        rand_infohash_key()
+    }
+}
+
+// the target itself with its last bit flipped, the closest id to every remote
+// which is not its own.
+#[derive(Debug)]
+pub struct Mirrored;
+
+impl NodeIdStrategy for Mirrored {
+    fn node_id(&self, target: &[u8], _local: &[u8]) -> Vec<u8> {
+        let mut id = target.to_vec();
+        if let Some(x) = id.last_mut() {
+            *x ^= 1;
+        }
+        id
+    }
+}
+
+// the strategy of name, one of local, neighbor, random or mirror. closeness
+// only applies to neighbor.
+pub fn from_name(name: &str, closeness: usize) -> Result<Arc<dyn NodeIdStrategy>> {
+    match name {
+        "local" => Ok(Arc::new(LocalId)),
+        "neighbor" => Ok(Arc::new(Neighbor { closeness })),
+        "random" => Ok(Arc::new(RandomId)),
+        "mirror" => Ok(Arc::new(Mirrored)),
+        _ => Err(Error::Other(format!("unknown node id strategy '{}'", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategies() {
+        let target = rand_infohash_key();
+        let local = rand_infohash_key();
+        let node_id =
+            |name, closeness| from_name(name, closeness).unwrap().node_id(&target, &local);
+
+        assert_eq!(node_id("local", 0), local);
+
+        let id = node_id("neighbor", 4);
+        assert_eq!(id[..4], target[..4]);
+        assert_eq!(id[4..], local[4..]);
+        assert_eq!(node_id("neighbor", 30), target);
+
+        assert_ne!(node_id("random", 0), node_id("random", 0));
+
+        let id = node_id("mirror", 0);
+        assert_eq!(id[..19], target[..19]);
+        assert_eq!(id[19], target[19] ^ 1);
+
+        assert!(from_name("bogus", 0).is_err());
+    }
+}
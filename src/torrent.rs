@@ -15,6 +15,11 @@ pub struct Torrent {
     pub name: String,
     pub length: i64,
     pub files: Vec<TorFile>,
+    // estimated swarm size from a DHT scrape, if done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seeders: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leechers: Option<u64>,
 }
 
 // extract torrent inter path.
@@ -94,5 +99,7 @@ pub fn from_bytes(infohash_hex: String, meta: &[u8]) -> Result<Torrent> {
         name,
         length,
         files,
+        seeders: None,
+        leechers: None,
     })
 }
@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::channel::Sender;
+use lru_cache::LruCache;
+
+use crate::krpc::Response;
+use crate::util::rand_transation_id;
+
+// replies arriving later than this are ignored.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// response to a query.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub addr: SocketAddr,
+    pub r: Response,
+}
+
+// outstanding query, replies go to the reply channel when it's set.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub identity: usize,
+    pub addr: SocketAddr,
+    pub reply: Option<Sender<Reply>>,
+    sent: Instant,
+}
+
+// outstanding queries keyed by transaction id.
+#[derive(Clone, Debug)]
+pub struct Transactions {
+    cache: Arc<Mutex<LruCache<Vec<u8>, Transaction>>>,
+}
+
+impl Transactions {
+    // capacity must stay below the transaction id space.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    // register a query sent to addr at now, returns a fresh transaction id.
+    pub fn insert(
+        &self,
+        identity: usize,
+        addr: SocketAddr,
+        reply: Option<Sender<Reply>>,
+        now: Instant,
+    ) -> Vec<u8> {
+        let mut cache = self.cache.lock().unwrap();
+
+        let mut tid = rand_transation_id();
+        while cache.contains_key(&tid) {
+            tid = rand_transation_id();
+        }
+
+        let t = Transaction {
+            identity,
+            addr,
+            reply,
+            sent: now,
+        };
+        cache.insert(tid.clone(), t);
+        tid
+    }
+
+    // take the query answered by a reply from addr at now.
+    pub fn remove(&self, tid: &[u8], addr: &SocketAddr, now: Instant) -> Option<Transaction> {
+        let mut cache = self.cache.lock().unwrap();
+
+        match cache.get_mut(tid) {
+            Some(t) if t.addr == *addr => {}
+            _ => return None,
+        }
+
+        cache
+            .remove(tid)
+            .filter(|t| now.saturating_duration_since(t.sent) < TRANSACTION_TIMEOUT)
+    }
+
+    // take the queries left unanswered for too long at now.
+    pub fn expire(&self, now: Instant) -> Vec<Transaction> {
+        let mut cache = self.cache.lock().unwrap();
+
+        let expired: Vec<Vec<u8>> = cache
+            .iter()
+            .filter(|(_, t)| now.saturating_duration_since(t.sent) >= TRANSACTION_TIMEOUT)
+            .map(|(tid, _)| tid.clone())
+            .collect();
+        expired.iter().filter_map(|tid| cache.remove(tid)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transactions() {
+        let transactions = Transactions::new(16);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let other = "127.0.0.1:6882".parse().unwrap();
+
+        let now = Instant::now();
+
+        let tid = transactions.insert(3, addr, None, now);
+
+        // replies from another address don't match.
+        assert!(transactions.remove(&tid, &other, now).is_none());
+
+        let t = transactions.remove(&tid, &addr, now).unwrap();
+        assert_eq!(t.identity, 3);
+
+        // a transaction is answered only once.
+        assert!(transactions.remove(&tid, &addr, now).is_none());
+
+        // late replies are dropped.
+        let tid = transactions.insert(3, addr, None, now);
+        let late = now + TRANSACTION_TIMEOUT;
+        assert!(transactions.remove(&tid, &addr, late).is_none());
+
+        // unanswered queries expire once.
+        transactions.insert(3, addr, None, now);
+        assert!(transactions.expire(now).is_empty());
+        assert_eq!(transactions.expire(late).len(), 1);
+        assert!(transactions.expire(late).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use log::info;
+
+pub(crate) type IoFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+// the outcome of every datagram of a batch, in order.
+pub(crate) type BatchFuture<'a> = Pin<Box<dyn Future<Output = Vec<io::Result<usize>>> + Send + 'a>>;
+
+// a datagram and its destination or source.
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+// datagram transport under a DHT.
+pub trait Transport: Send + Sync + Debug {
+    fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> IoFuture<'a, usize>;
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> IoFuture<'a, (usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    // wait for datagrams and receive as many as fit in bufs, at least one.
+    // returns their lengths and sources, the default receives one.
+    fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>]) -> IoFuture<'a, Vec<(usize, SocketAddr)>> {
+        Box::pin(async move { Ok(vec![self.recv_from(&mut bufs[0]).await?]) })
+    }
+
+    // send all the datagrams, one failing does not stop the rest. the default
+    // sends them one by one.
+    fn send_batch<'a>(&'a self, msgs: &'a [Datagram]) -> BatchFuture<'a> {
+        Box::pin(async move {
+            let mut res = Vec::with_capacity(msgs.len());
+            for (buf, addr) in msgs {
+                res.push(self.send_to(buf, *addr).await);
+            }
+            res
+        })
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> IoFuture<'a, usize> {
+        Box::pin(UdpSocket::send_to(self, buf, addr))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> IoFuture<'a, (usize, SocketAddr)> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// n udp sockets bound to addr, sharing the port with SO_REUSEPORT so the kernel
+// spreads the datagrams over them. other systems than linux get one socket.
+// with the mmsg feature on linux, they receive and send in batches.
+pub async fn bind(addr: &str, n: usize) -> io::Result<Vec<Box<dyn Transport>>> {
+    let n = if cfg!(target_os = "linux") {
+        n.max(1)
+    } else {
+        if n > 1 {
+            info!("SO_REUSEPORT needs linux, bind one socket.");
+        }
+        1
+    };
+
+    let mut addr = addr
+        .to_socket_addrs()
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+
+    let mut socks = Vec::with_capacity(n);
+    for _ in 0..n {
+        let sock = match n {
+            1 => std::net::UdpSocket::bind(addr)?,
+            _ => bind_reuse_port(addr)?,
+        };
+        // the rest share the port picked for the first.
+        addr = sock.local_addr()?;
+        socks.push(wrap(sock)?);
+    }
+    Ok(socks)
+}
+
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+fn wrap(sock: std::net::UdpSocket) -> io::Result<Box<dyn Transport>> {
+    Ok(Box::new(crate::mmsg::MmsgSocket::new(sock)?))
+}
+
+#[cfg(not(all(feature = "mmsg", target_os = "linux")))]
+fn wrap(sock: std::net::UdpSocket) -> io::Result<Box<dyn Transport>> {
+    Ok(Box::new(UdpSocket::from(sock)))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_port(true)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    std::net::UdpSocket::bind(addr)
+}
+
+// in-memory network of transports, for running DHTs without sockets.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork {
+    hosts: Arc<Mutex<HashMap<SocketAddr, Sender<Datagram>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // a transport receiving the datagrams sent to addr.
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport {
+        let (tx, rx) = channel::unbounded();
+        self.hosts.lock().unwrap().insert(addr, tx);
+
+        MemoryTransport {
+            addr,
+            network: self.clone(),
+            rx,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    rx: Receiver<Datagram>,
+}
+
+impl Transport for MemoryTransport {
+    // like udp, datagrams to nowhere are lost silently and the other address
+    // family can't be reached.
+    fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> IoFuture<'a, usize> {
+        if addr.is_ipv4() != self.addr.is_ipv4() {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "address family not supported");
+            return Box::pin(async move { Err(e) });
+        }
+
+        let host = self.network.hosts.lock().unwrap().get(&addr).cloned();
+        if let Some(host) = host {
+            let _ = host.try_send((buf.to_vec(), self.addr));
+        }
+        Box::pin(async move { Ok(buf.len()) })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    // like udp, datagrams longer than buf are truncated.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> IoFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            let (data, from) = self
+                .rx
+                .recv()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok((n, from))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future, task};
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_transport() {
+        let network = MemoryNetwork::new();
+        let a = network.bind("10.0.0.1:6881".parse().unwrap());
+        let b = network.bind("10.0.0.2:6881".parse().unwrap());
+
+        task::block_on(async {
+            a.send_to(b"hello", "10.0.0.2:6881".parse().unwrap())
+                .await
+                .unwrap();
+            a.send_to(b"lost", "10.0.0.3:6881".parse().unwrap())
+                .await
+                .unwrap();
+
+            let mut buf = [0; 4];
+            let (n, from) = b.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hell");
+            assert_eq!(from, "10.0.0.1:6881".parse().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_batch() {
+        let network = MemoryNetwork::new();
+        let a = network.bind("10.0.0.1:6881".parse().unwrap());
+        let b = network.bind("10.0.0.2:6881".parse().unwrap());
+
+        task::block_on(async {
+            let to = b.local_addr().unwrap();
+            // the ipv6 datagram fails alone.
+            let msgs = vec![
+                (b"one".to_vec(), to),
+                (b"six".to_vec(), "[::1]:6881".parse().unwrap()),
+                (b"two".to_vec(), to),
+            ];
+            let res = a.send_batch(&msgs).await;
+            assert_eq!(res.len(), 3);
+            assert!(res[0].is_ok() && res[1].is_err() && res[2].is_ok());
+
+            let mut bufs = vec![vec![0; 8]; 4];
+            for expect in [b"one", b"two"] {
+                let got = b.recv_batch(&mut bufs).await.unwrap();
+                assert_eq!(got, vec![(3, a.local_addr().unwrap())]);
+                assert_eq!(&bufs[0][..3], expect);
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_reuse_port() {
+        task::block_on(async {
+            let socks = bind("127.0.0.1:0", 3).await.unwrap();
+            let addr = socks[0].local_addr().unwrap();
+            assert!(socks.iter().all(|s| s.local_addr().unwrap() == addr));
+
+            // one of them holds the datagram.
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(b"ping", addr).await.unwrap();
+            let mut received = 0;
+            for s in socks.iter() {
+                let mut buf = [0; 4];
+                let wait = Duration::from_millis(200);
+                if let Ok(Ok((n, _))) = future::timeout(wait, s.recv_from(&mut buf)).await {
+                    assert_eq!(&buf[..n], b"ping");
+                    received += 1;
+                }
+            }
+            assert_eq!(received, 1);
+        });
+    }
+}
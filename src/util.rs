@@ -1,12 +1,9 @@
 use rand::RngCore;
-use std::ptr::copy;
 
 // infohash length.
 const KEY_LENGTH: usize = 20;
 // transaction id length.
 const TID_LENGTH: usize = 2;
-// neighbor id length.
-const CLOSENESS: usize = 15;
 
 // make random infohash key.
 pub fn rand_infohash_key() -> Vec<u8> {
@@ -18,19 +15,62 @@ pub fn rand_transation_id() -> Vec<u8> {
     rand_bytes(TID_LENGTH)
 }
 
-pub fn neighbor_id(target: &[u8], local: &[u8]) -> Vec<u8> {
-    let mut id = vec![0; KEY_LENGTH];
-    unsafe {
-        copy(target.as_ptr(), id.as_mut_ptr(), CLOSENESS);
-        copy(
-            local.as_ptr().offset(CLOSENESS as isize),
-            id.as_mut_ptr().offset(CLOSENESS as isize),
-            KEY_LENGTH - CLOSENESS,
-        );
-    }
+// the first closeness bytes of target followed by the rest of local.
+pub fn neighbor_id(target: &[u8], local: &[u8], closeness: usize) -> Vec<u8> {
+    let n = closeness.min(KEY_LENGTH);
+    let mut id = target[..n].to_vec();
+    id.extend_from_slice(&local[n..KEY_LENGTH]);
     id
 }
 
+// xor distance between two keys.
+pub fn distance(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+// number of leading bits shared by two keys.
+pub fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        let d = x ^ y;
+        if d != 0 {
+            return i * 8 + d.leading_zeros() as usize;
+        }
+    }
+    a.len().min(b.len()) * 8
+}
+
+// random key sharing exactly the first n bits with id.
+pub fn rand_id_in_bucket(id: &[u8], n: usize) -> Vec<u8> {
+    let mut res = rand_bytes(id.len());
+    let (byte, bit) = (n / 8, n % 8);
+    if byte >= id.len() {
+        return id.to_vec();
+    }
+
+    res[..byte].copy_from_slice(&id[..byte]);
+    // keep the bits before n, flip bit n, the rest stays random.
+    let (mask, flip) = (0xff >> bit, 0x80 >> bit);
+    res[byte] = (id[byte] & !mask) | (!id[byte] & flip) | (res[byte] & (mask ^ flip));
+    res
+}
+
+// encode bytes as lowercase hex string.
+pub fn to_hex(s: &[u8]) -> String {
+    s.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// decode hex string into bytes.
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect()
+}
+
 // make random bytes id.
 fn rand_bytes(n: usize) -> Vec<u8> {
     let mut rng = rand::thread_rng();
@@ -61,12 +101,39 @@ mod tests {
         assert_ne!(id1, id2);
     }
 
+    #[test]
+    fn test_rand_id_in_bucket() {
+        let id = rand_infohash_key();
+        for n in [0, 7, 8, 13, 159] {
+            assert_eq!(common_prefix_len(&rand_id_in_bucket(&id, n), &id), n);
+        }
+    }
+
     #[test]
     fn test_neighbor_id() {
         let target = rand_infohash_key();
         let local = rand_infohash_key();
-        let res = neighbor_id(&target, &local);
-        assert_eq!(res[..CLOSENESS], target[..CLOSENESS]);
-        assert_eq!(res[CLOSENESS..], local[CLOSENESS..]);
+        let res = neighbor_id(&target, &local, 15);
+        assert_eq!(res[..15], target[..15]);
+        assert_eq!(res[15..], local[15..]);
+        assert_eq!(neighbor_id(&target, &local, 0), local);
+    }
+
+    #[test]
+    fn test_common_prefix_len() {
+        assert_eq!(common_prefix_len(&[0x00, 0x00], &[0x00, 0x00]), 16);
+        assert_eq!(common_prefix_len(&[0x80, 0x00], &[0x00, 0x00]), 0);
+        assert_eq!(common_prefix_len(&[0x0f, 0x00], &[0x0e, 0x00]), 7);
+        assert_eq!(common_prefix_len(&[0x0f, 0x01], &[0x0f, 0x00]), 15);
+    }
+
+    #[test]
+    fn test_hex() {
+        let key = rand_infohash_key();
+        assert_eq!(from_hex(&to_hex(&key)), Some(key));
+        assert_eq!(to_hex(&[0x0a, 0xff]), "0aff");
+        assert_eq!(from_hex("0aff"), Some(vec![0x0a, 0xff]));
+        assert_eq!(from_hex("0af"), None);
+        assert_eq!(from_hex("zz"), None);
     }
 }